@@ -0,0 +1,198 @@
+use std::time::{Duration, Instant};
+
+use super::structs::{PushDataPayload, RxPk, Stat};
+
+// Pending PUSH_DATA payload, aggregating the rxpk and stat objects received within
+// the aggregation interval into a single PUSH_DATA datagram.
+#[derive(Default)]
+pub struct PendingPushData {
+    payload: PushDataPayload,
+    since: Option<Instant>,
+}
+
+impl PendingPushData {
+    pub fn is_empty(&self) -> bool {
+        self.payload.rxpk.is_empty() && self.payload.stat.is_none()
+    }
+
+    pub fn is_due(&self, interval: Duration) -> bool {
+        match self.since {
+            Some(v) => v.elapsed() >= interval,
+            None => false,
+        }
+    }
+
+    pub fn take(&mut self) -> PushDataPayload {
+        self.since = None;
+        std::mem::take(&mut self.payload)
+    }
+
+    // Add the rxpk to the pending payload. Returns the payloads that must be sent
+    // immediately, as adding the rxpk would exceed the max. datagram size. In that
+    // case a new aggregation window is started with the rxpk. A single rxpk
+    // exceeding the max. datagram size can not be split and is returned as-is.
+    pub fn push_rxpk(&mut self, rxpk: RxPk, max_size: usize) -> Vec<PushDataPayload> {
+        let mut out = vec![];
+
+        self.payload.rxpk.push(rxpk);
+        self.since.get_or_insert_with(Instant::now);
+
+        if push_data_size(&self.payload) <= max_size {
+            return out;
+        }
+
+        if self.payload.rxpk.len() > 1 || self.payload.stat.is_some() {
+            let rxpk = self.payload.rxpk.pop().unwrap();
+            out.push(self.take());
+
+            self.payload.rxpk.push(rxpk);
+            self.since = Some(Instant::now());
+        }
+
+        if push_data_size(&self.payload) > max_size {
+            out.push(self.take());
+        }
+
+        out
+    }
+
+    // Set the stat of the pending payload. In case a stat is already pending, its
+    // counters are added to the given stat. Returns the payloads that must be sent
+    // immediately, as adding the stat would exceed the max. datagram size.
+    pub fn set_stat(&mut self, mut stat: Stat, max_size: usize) -> Vec<PushDataPayload> {
+        let mut out = vec![];
+
+        if let Some(pending) = self.payload.stat.take() {
+            stat.rxnb += pending.rxnb;
+            stat.rxok += pending.rxok;
+            stat.rxfw += pending.rxfw;
+            stat.dwnb += pending.dwnb;
+            stat.txnb += pending.txnb;
+        }
+
+        self.payload.stat = Some(stat);
+        self.since.get_or_insert_with(Instant::now);
+
+        if !self.payload.rxpk.is_empty() && push_data_size(&self.payload) > max_size {
+            let stat = self.payload.stat.take();
+            out.push(self.take());
+
+            self.payload.stat = stat;
+            self.since = Some(Instant::now());
+        }
+
+        out
+    }
+}
+
+pub fn push_data_size(payload: &PushDataPayload) -> usize {
+    // 12 bytes header + JSON payload.
+    12 + serde_json::to_vec(payload)
+        .map(|v| v.len())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use chirpstack_api::gw;
+
+    use super::*;
+
+    fn rxpk(size: usize) -> RxPk {
+        RxPk::from_proto(&gw::UplinkFrame {
+            phy_payload: vec![0; size],
+            tx_info: Some(gw::UplinkTxInfo {
+                frequency: 868100000,
+                modulation: Some(gw::Modulation {
+                    parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                        bandwidth: 125000,
+                        spreading_factor: 7,
+                        code_rate: gw::CodeRate::Cr45.into(),
+                        ..Default::default()
+                    })),
+                }),
+            }),
+            rx_info: Some(gw::UplinkRxInfo {
+                context: vec![0, 0, 0, 0],
+                crc_status: gw::CrcStatus::CrcOk.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn stat(rxnb: u32) -> Stat {
+        Stat::from_proto(&gw::GatewayStats {
+            rx_packets_received: rxnb,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_push_rxpk() {
+        let mut p = PendingPushData::default();
+        let max_size = push_data_size(&PushDataPayload {
+            rxpk: vec![rxpk(10), rxpk(10)],
+            stat: None,
+        });
+
+        assert!(p.push_rxpk(rxpk(10), max_size).is_empty());
+        assert!(p.push_rxpk(rxpk(10), max_size).is_empty());
+
+        // The third rxpk exceeds the max. size, the first two are returned and the
+        // third starts a new aggregation window.
+        let out = p.push_rxpk(rxpk(10), max_size);
+        assert_eq!(1, out.len());
+        assert_eq!(2, out[0].rxpk.len());
+        assert_eq!(1, p.payload.rxpk.len());
+        assert!(p.since.is_some());
+    }
+
+    #[test]
+    fn test_push_rxpk_exceeds_max_size() {
+        let mut p = PendingPushData::default();
+        let max_size = push_data_size(&PushDataPayload {
+            rxpk: vec![rxpk(10)],
+            stat: None,
+        });
+
+        assert!(p.push_rxpk(rxpk(10), max_size).is_empty());
+
+        // The pending rxpk and the oversized rxpk are both returned.
+        let out = p.push_rxpk(rxpk(100), max_size);
+        assert_eq!(2, out.len());
+        assert_eq!(1, out[0].rxpk.len());
+        assert_eq!(1, out[1].rxpk.len());
+        assert!(p.is_empty());
+        assert!(p.since.is_none());
+    }
+
+    #[test]
+    fn test_set_stat() {
+        let mut p = PendingPushData::default();
+
+        assert!(p.set_stat(stat(1), 1400).is_empty());
+        assert!(p.set_stat(stat(2), 1400).is_empty());
+        assert!(p.push_rxpk(rxpk(10), 1400).is_empty());
+
+        let payload = p.take();
+        assert_eq!(3, payload.stat.unwrap().rxnb);
+        assert_eq!(1, payload.rxpk.len());
+        assert!(p.is_empty());
+    }
+
+    #[test]
+    fn test_is_due() {
+        let mut p = PendingPushData::default();
+        assert!(!p.is_due(Duration::ZERO));
+
+        p.push_rxpk(rxpk(10), 1400);
+        assert!(p.is_due(Duration::ZERO));
+        assert!(!p.is_due(Duration::from_secs(60)));
+
+        p.take();
+        assert!(!p.is_due(Duration::ZERO));
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+// Exponential backoff with jitter. Each retry doubles the delay, capped at the
+// max. delay. The returned delay is randomized between 50% and 100% of the delay,
+// to avoid that multiple clients retry at the same moment.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    // Returns the delay before the next retry.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+
+        let half = delay / 2;
+        half + half.mul_f64(rand::rng().random::<f64>())
+    }
+
+    // Reset the backoff, e.g. after a successful attempt.
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(4));
+
+        for max in [1, 2, 4, 4] {
+            let max = Duration::from_secs(max);
+            let delay = b.next_delay();
+            assert!(delay >= max / 2 && delay <= max);
+        }
+
+        b.reset();
+        assert!(b.next_delay() <= Duration::from_secs(1));
+    }
+}
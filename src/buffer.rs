@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Unacknowledged PUSH_DATA datagram. Only the JSON payload is stored, the header
+// is re-created when re-sending so that it contains the current gateway ID.
+struct Item {
+    token: u16,
+    data: Vec<u8>,
+    created_at: SystemTime,
+    sent_at: Option<Instant>,
+}
+
+// Representation of an Item when stored on disk.
+#[derive(Serialize, Deserialize)]
+struct StoredItem {
+    token: u16,
+    data: String,
+    created_at: u64,
+}
+
+// Store-and-forward buffer for PUSH_DATA datagrams that have not (yet) been
+// acknowledged by the server.
+pub struct Buffer {
+    max_size: usize,
+    max_age: Duration,
+    path: String,
+    items: VecDeque<Item>,
+    dirty: bool,
+}
+
+impl Buffer {
+    pub fn new(max_size: usize, max_age: Duration, path: &str) -> Self {
+        Buffer {
+            max_size,
+            max_age,
+            path: path.to_string(),
+            items: VecDeque::new(),
+            dirty: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_size != 0
+    }
+
+    // Add the payload of a sent PUSH_DATA datagram. In case the buffer is full, the
+    // oldest item is dropped and returned true.
+    pub fn push(&mut self, token: u16, data: Vec<u8>) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+
+        let mut dropped = false;
+        if self.items.len() >= self.max_size {
+            self.items.pop_front();
+            dropped = true;
+        }
+
+        self.items.push_back(Item {
+            token,
+            data,
+            created_at: SystemTime::now(),
+            sent_at: Some(Instant::now()),
+        });
+        self.dirty = true;
+
+        dropped
+    }
+
+    // Returns true if the token is used by one of the items.
+    pub fn contains(&self, token: u16) -> bool {
+        self.items.iter().any(|v| v.token == token)
+    }
+
+    // Remove the item matching the acknowledged token. Returns true if found.
+    pub fn ack(&mut self, token: u16) -> bool {
+        match self.items.iter().position(|v| v.token == token) {
+            Some(i) => {
+                self.items.remove(i);
+                self.dirty = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Remove the items exceeding the max. age. Returns the number of removed items.
+    pub fn remove_expired(&mut self) -> usize {
+        let len = self.items.len();
+        let max_age = self.max_age;
+        self.items.retain(|v| match v.created_at.elapsed() {
+            Ok(age) => max_age.is_zero() || age <= max_age,
+            Err(_) => true,
+        });
+
+        let removed = len - self.items.len();
+        if removed != 0 {
+            self.dirty = true;
+        }
+        removed
+    }
+
+    // Remove the items that have not been acknowledged within the given timeout since
+    // they were last sent, e.g. when the PUSH_ACK was lost while the server was
+    // reachable. Returns the number of removed items.
+    pub fn remove_unacked(&mut self, ack_timeout: Duration) -> usize {
+        let len = self.items.len();
+        self.items
+            .retain(|v| v.sent_at.is_none_or(|v| v.elapsed() < ack_timeout));
+
+        let removed = len - self.items.len();
+        if removed != 0 {
+            self.dirty = true;
+        }
+        removed
+    }
+
+    // Return the (token, payload) of the items, up to the given limit, that have not
+    // been acknowledged within the given timeout since they were last sent. The items
+    // that were never sent, or sent the longest ago, are returned first so that a
+    // limited batch does not starve the other items. These items are marked as sent.
+    pub fn get_retransmit(&mut self, ack_timeout: Duration, limit: usize) -> Vec<(u16, Vec<u8>)> {
+        let mut pending: Vec<usize> = (0..self.items.len())
+            .filter(|i| match self.items[*i].sent_at {
+                Some(v) => v.elapsed() >= ack_timeout,
+                None => true,
+            })
+            .collect();
+        pending.sort_by_key(|i| self.items[*i].sent_at);
+        pending.truncate(limit);
+        pending.sort();
+
+        let now = Instant::now();
+        pending
+            .into_iter()
+            .map(|i| {
+                let item = &mut self.items[i];
+                item.sent_at = Some(now);
+                (item.token, item.data.clone())
+            })
+            .collect()
+    }
+
+    pub fn load(&mut self) -> Result<()> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+
+        let content = match fs::read_to_string(&self.path) {
+            Ok(v) => v,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(anyhow!("read buffer file error: {}", err)),
+        };
+
+        let stored: Vec<StoredItem> = serde_json::from_str(&content)?;
+        for item in stored {
+            if self.items.len() >= self.max_size {
+                break;
+            }
+
+            self.items.push_back(Item {
+                token: item.token,
+                data: hex::decode(&item.data)?,
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(item.created_at),
+                sent_at: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        if self.path.is_empty() || !self.dirty {
+            return Ok(());
+        }
+
+        let stored: Vec<StoredItem> = self
+            .items
+            .iter()
+            .map(|v| StoredItem {
+                token: v.token,
+                data: hex::encode(&v.data),
+                created_at: v
+                    .created_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|v| v.as_secs())
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        // Write to a temporary file first, so that we never end up with a partially
+        // written buffer file.
+        let tmp_path = format!("{}.tmp", self.path);
+        fs::write(&tmp_path, serde_json::to_vec(&stored)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+// Returns the token following the given token, skipping the tokens that are in use
+// (e.g. by buffered datagrams), so that a PUSH_ACK can not match multiple datagrams.
+pub fn next_token(token: u16, in_use: impl Fn(u16) -> bool) -> u16 {
+    let mut next = token.wrapping_add(1);
+    for _ in 0..u16::MAX {
+        if !in_use(next) {
+            break;
+        }
+        next = next.wrapping_add(1);
+    }
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer() {
+        let mut b = Buffer::new(2, Duration::from_secs(60), "");
+
+        assert!(!b.push(1, vec![1]));
+        assert!(!b.push(2, vec![2]));
+        assert!(b.push(3, vec![3]));
+        assert_eq!(2, b.items.len());
+
+        assert!(!b.ack(1));
+        assert!(b.ack(2));
+        assert_eq!(1, b.items.len());
+
+        assert!(b.get_retransmit(Duration::from_secs(60), 10).is_empty());
+        assert_eq!(
+            vec![(3, vec![3])],
+            b.get_retransmit(Duration::from_secs(0), 10)
+        );
+    }
+
+    #[test]
+    fn test_buffer_retransmit_limit() {
+        let mut b = Buffer::new(10, Duration::from_secs(60), "");
+        b.push(1, vec![1]);
+        b.push(2, vec![2]);
+        b.push(3, vec![3]);
+
+        // The items sent the longest ago are returned first, the others at the next
+        // call.
+        assert_eq!(
+            vec![(1, vec![1]), (2, vec![2])],
+            b.get_retransmit(Duration::from_secs(0), 2)
+        );
+        assert_eq!(
+            vec![(1, vec![1]), (3, vec![3])],
+            b.get_retransmit(Duration::from_secs(0), 2)
+        );
+    }
+
+    #[test]
+    fn test_buffer_remove_unacked() {
+        let mut b = Buffer::new(10, Duration::from_secs(60), "");
+        b.push(1, vec![1]);
+
+        assert_eq!(0, b.remove_unacked(Duration::from_secs(60)));
+        assert_eq!(1, b.remove_unacked(Duration::from_secs(0)));
+        assert!(b.items.is_empty());
+    }
+
+    #[test]
+    fn test_next_token() {
+        let mut b = Buffer::new(10, Duration::from_secs(60), "");
+        b.push(1, vec![1]);
+        b.push(2, vec![2]);
+
+        // The tokens of the buffered datagrams are skipped.
+        let token = next_token(0, |v| b.contains(v));
+        assert_eq!(3, token);
+        b.push(token, vec![3]);
+
+        // The PUSH_ACK of the new datagram does not remove an older datagram.
+        assert!(b.ack(token));
+        assert_eq!(
+            vec![(1, vec![1]), (2, vec![2])],
+            b.get_retransmit(Duration::from_secs(0), 10)
+        );
+
+        assert_eq!(0, next_token(u16::MAX, |v| b.contains(v)));
+        assert_eq!(3, next_token(u16::MAX, |v| v < 3));
+    }
+
+    #[test]
+    fn test_buffer_disabled() {
+        let mut b = Buffer::new(0, Duration::from_secs(60), "");
+        assert!(!b.push(1, vec![1]));
+        assert_eq!(0, b.items.len());
+    }
+
+    #[test]
+    fn test_buffer_load_save() {
+        let path = std::env::temp_dir().join(format!("buffer-{}.json", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        let mut b = Buffer::new(10, Duration::from_secs(60), path);
+        b.push(1, vec![1, 2, 3]);
+        b.save().unwrap();
+
+        let mut b = Buffer::new(10, Duration::from_secs(60), path);
+        b.load().unwrap();
+        assert_eq!(
+            vec![(1, vec![1, 2, 3])],
+            b.get_retransmit(Duration::from_secs(60), 10)
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
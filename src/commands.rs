@@ -1,8 +1,22 @@
-use anyhow::Result;
+use std::time::Duration;
+
+use chirpstack_api::{gw, prost::Message};
 
 use super::socket::ZMQ_CONTEXT;
 
-pub fn get_socket(endpoint: &str) -> Result<zmq::Socket> {
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Timeout")]
+    Timeout,
+
+    #[error(transparent)]
+    Zmq(#[from] zmq::Error),
+
+    #[error(transparent)]
+    Decode(#[from] chirpstack_api::prost::DecodeError),
+}
+
+pub fn get_socket(endpoint: &str) -> Result<zmq::Socket, zmq::Error> {
     info!(
         "Creating new socket for sending commands, endpoint: {}",
         endpoint
@@ -10,7 +24,156 @@ pub fn get_socket(endpoint: &str) -> Result<zmq::Socket> {
 
     let zmq_ctx = ZMQ_CONTEXT.lock().unwrap();
     let sock = zmq_ctx.socket(zmq::REQ)?;
+    sock.set_linger(0)?;
     sock.connect(endpoint)?;
 
     Ok(sock)
 }
+
+// Client for sending commands to Concentratord. As a REQ socket can not be used
+// anymore when a reply was never received, the socket is re-created on errors.
+pub struct Client {
+    endpoint: String,
+    timeout: Duration,
+    retries: u32,
+    sock: zmq::Socket,
+}
+
+impl Client {
+    pub fn new(endpoint: &str, timeout: Duration, retries: u32) -> Result<Self, Error> {
+        Ok(Client {
+            endpoint: endpoint.to_string(),
+            timeout,
+            retries,
+            sock: get_socket(endpoint)?,
+        })
+    }
+
+    pub fn get_gateway_id(&mut self) -> Result<gw::GetGatewayIdResponse, Error> {
+        let b = self.send(
+            &gw::Command {
+                command: Some(gw::command::Command::GetGatewayId(
+                    gw::GetGatewayIdRequest {},
+                )),
+            },
+            self.retries,
+        )?;
+
+        Ok(gw::GetGatewayIdResponse::decode(b.as_slice())?)
+    }
+
+    // The downlink is sent at most once, as on a timeout it is unknown if the
+    // Concentratord did receive (and transmit) the downlink.
+    pub fn send_downlink_frame(
+        &mut self,
+        pl: gw::DownlinkFrame,
+    ) -> Result<gw::DownlinkTxAck, Error> {
+        let b = self.send(
+            &gw::Command {
+                command: Some(gw::command::Command::SendDownlinkFrame(pl)),
+            },
+            0,
+        )?;
+
+        Ok(gw::DownlinkTxAck::decode(b.as_slice())?)
+    }
+
+    // Send the command and return the response. On errors, the socket is re-created
+    // and the command is retried up to the given number of retries.
+    fn send(&mut self, cmd: &gw::Command, retries: u32) -> Result<Vec<u8>, Error> {
+        let b = cmd.encode_to_vec();
+        let mut attempt = 0;
+
+        loop {
+            let err = match self.send_once(&b) {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+
+            warn!(
+                "Send command error: {}, endpoint: {}, attempt: {}",
+                err,
+                self.endpoint,
+                attempt + 1
+            );
+            self.sock = get_socket(&self.endpoint)?;
+
+            if attempt >= retries {
+                return Err(err);
+            }
+            attempt += 1;
+        }
+    }
+
+    fn send_once(&self, b: &[u8]) -> Result<Vec<u8>, Error> {
+        self.sock.send(b, 0)?;
+
+        // set poller so that we can timeout
+        let mut items = [self.sock.as_poll_item(zmq::POLLIN)];
+        zmq::poll(&mut items, self.timeout.as_millis() as i64)?;
+        if !items[0].is_readable() {
+            return Err(Error::Timeout);
+        }
+
+        Ok(self.sock.recv_bytes(0)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_client_timeout_recovery() {
+        let rep = ZMQ_CONTEXT.lock().unwrap().socket(zmq::REP).unwrap();
+        rep.bind("inproc://commands_test").unwrap();
+
+        let mut client =
+            Client::new("inproc://commands_test", Duration::from_millis(200), 0).unwrap();
+
+        // No reply, the socket is re-created.
+        assert!(matches!(client.get_gateway_id(), Err(Error::Timeout)));
+
+        let server = thread::spawn(move || {
+            // Reply to the timed-out request, this reply is lost.
+            rep.recv_bytes(0).unwrap();
+            rep.send(vec![], 0).unwrap();
+
+            rep.recv_bytes(0).unwrap();
+            let resp = gw::GetGatewayIdResponse {
+                gateway_id: "0102030405060708".into(),
+            };
+            rep.send(resp.encode_to_vec(), 0).unwrap();
+        });
+
+        assert_eq!(
+            "0102030405060708",
+            client.get_gateway_id().unwrap().gateway_id
+        );
+        server.join().unwrap();
+    }
+    #[test]
+    fn test_client_downlink_no_retry() {
+        let rep = ZMQ_CONTEXT.lock().unwrap().socket(zmq::REP).unwrap();
+        rep.bind("inproc://commands_test_downlink").unwrap();
+
+        let mut client = Client::new(
+            "inproc://commands_test_downlink",
+            Duration::from_millis(100),
+            2,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            client.send_downlink_frame(gw::DownlinkFrame::default()),
+            Err(Error::Timeout)
+        ));
+
+        // The downlink was sent only once, regardless of the retries.
+        rep.recv_bytes(0).unwrap();
+        rep.send(vec![], 0).unwrap();
+        assert_eq!(0, rep.poll(zmq::POLLIN, 100).unwrap());
+    }
+}
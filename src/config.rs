@@ -1,8 +1,12 @@
+use std::str::FromStr;
 use std::{env, fs};
 
 use anyhow::Result;
 use serde::Deserialize;
 
+use super::filters::{DevAddrPrefix, EuiRange, NetId};
+use super::structs::{Modulation, PROTOCOL_VERSION_1, PROTOCOL_VERSION_2};
+
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Deserialize)]
@@ -26,42 +30,176 @@ impl Default for UdpForwarder {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum DownlinkMode {
+    Allow,
+    Deny,
+    DryRun,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum IpPreference {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct VirtualGateway {
+    pub gateway_id: String,
+    pub board: u32,
+    pub antenna: u32,
+    pub rf_chain: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
 #[serde(default)]
 pub struct Server {
     pub server: String,
+    pub server_port_down: u16,
+    pub ip_preference: IpPreference,
+    pub bind_address: String,
+    pub bind_port: u16,
+    pub bind_port_down: u16,
+    pub bind_interface: String,
+    pub dscp: u8,
+    pub socket_recv_buffer_size: usize,
+    pub socket_send_buffer_size: usize,
+    pub protocol_version: u8,
     pub keepalive_interval_secs: u64,
     pub keepalive_max_failures: u32,
+    pub downlink_mode: DownlinkMode,
+    pub failover_group: String,
+    pub failover_hold_down_secs: u64,
     pub forward_crc_ok: bool,
     pub forward_crc_invalid: bool,
     pub forward_crc_missing: bool,
+    pub frequency_min: u32,
+    pub frequency_max: u32,
+    pub modulations: Vec<Modulation>,
+    pub spreading_factors: Vec<u32>,
+    pub bandwidths: Vec<u32>,
+    pub min_rssi: Option<i32>,
+    pub min_snr: Option<f32>,
+    pub allow_dev_addr_prefixes: Vec<DevAddrPrefix>,
+    pub deny_dev_addr_prefixes: Vec<DevAddrPrefix>,
+    pub allow_net_ids: Vec<NetId>,
+    pub deny_net_ids: Vec<NetId>,
+    pub allow_join_eui_ranges: Vec<EuiRange>,
+    pub deny_join_eui_ranges: Vec<EuiRange>,
+    pub aggregation_interval_ms: u64,
+    pub max_push_data_size: usize,
+    pub buffer_size: usize,
+    pub buffer_max_age_secs: u64,
+    pub buffer_path: String,
+    pub buffer_retransmit_batch_size: usize,
+    pub pull_resp_dedup_ttl_secs: u64,
+    pub concentratords: Vec<String>,
+    pub gateway_id: String,
+    pub virtual_gateways: Vec<VirtualGateway>,
+}
+
+impl Server {
+    // Returns true if the server forwards the data of the given Concentratord.
+    pub fn forwards_concentratord(&self, name: &str) -> bool {
+        self.concentratords.is_empty() || self.concentratords.iter().any(|v| v == name)
+    }
+
+    // Returns the decoded gateway id override, or None if not configured.
+    pub fn get_gateway_id(&self) -> Result<Option<Vec<u8>>> {
+        if self.gateway_id.is_empty() {
+            return Ok(None);
+        }
+
+        decode_gateway_id(&self.gateway_id).map(Some)
+    }
 }
 
 impl Default for Server {
     fn default() -> Self {
         Server {
             server: "127.0.0.1:1700".into(),
+            server_port_down: 0,
+            ip_preference: IpPreference::Any,
+            bind_address: "".into(),
+            bind_port: 0,
+            bind_port_down: 0,
+            bind_interface: "".into(),
+            dscp: 0,
+            socket_recv_buffer_size: 0,
+            socket_send_buffer_size: 0,
+            protocol_version: PROTOCOL_VERSION_2,
             keepalive_interval_secs: 10,
             keepalive_max_failures: 12,
+            downlink_mode: DownlinkMode::Allow,
+            failover_group: "".into(),
+            failover_hold_down_secs: 300,
             forward_crc_ok: true,
             forward_crc_invalid: false,
             forward_crc_missing: false,
+            frequency_min: 0,
+            frequency_max: 0,
+            modulations: vec![],
+            spreading_factors: vec![],
+            bandwidths: vec![],
+            min_rssi: None,
+            min_snr: None,
+            allow_dev_addr_prefixes: vec![],
+            deny_dev_addr_prefixes: vec![],
+            allow_net_ids: vec![],
+            deny_net_ids: vec![],
+            allow_join_eui_ranges: vec![],
+            deny_join_eui_ranges: vec![],
+            aggregation_interval_ms: 0,
+            max_push_data_size: 1400,
+            buffer_size: 0,
+            buffer_max_age_secs: 3600,
+            buffer_path: "".into(),
+            buffer_retransmit_batch_size: 10,
+            pull_resp_dedup_ttl_secs: 10,
+            concentratords: vec![],
+            gateway_id: "".into(),
+            virtual_gateways: vec![],
         }
     }
 }
 
-#[derive(Deserialize)]
+fn decode_gateway_id(s: &str) -> Result<Vec<u8>> {
+    let id = hex::decode(s).map_err(|e| anyhow!("gateway_id decode error: {}", e))?;
+    if id.len() != 8 {
+        return Err(anyhow!("gateway_id must be 8 bytes, got: {}", id.len()));
+    }
+
+    Ok(id)
+}
+
+#[derive(Deserialize, Clone)]
 #[serde(default)]
 pub struct Concentratord {
+    pub name: String,
     pub event_url: String,
     pub command_url: String,
+    pub command_timeout_ms: u64,
+    pub command_retries: u32,
+    pub startup_timeout_secs: u64,
+    pub gateway_id_check_interval_secs: u64,
+    pub gateway_id_check_event_gap_secs: u64,
 }
 
 impl Default for Concentratord {
     fn default() -> Self {
         Concentratord {
+            name: "".to_string(),
             event_url: "ipc:///tmp/concentratord_event".to_string(),
             command_url: "ipc:///tmp/concentratord_command".to_string(),
+            command_timeout_ms: 100,
+            command_retries: 0,
+            startup_timeout_secs: 60,
+            gateway_id_check_interval_secs: 60,
+            gateway_id_check_event_gap_secs: 45,
         }
     }
 }
@@ -69,7 +207,10 @@ impl Default for Concentratord {
 #[derive(Deserialize)]
 pub struct Configuration {
     pub udp_forwarder: UdpForwarder,
+    #[serde(default)]
     pub concentratord: Concentratord,
+    #[serde(default)]
+    pub concentratords: Vec<Concentratord>,
 }
 
 impl Configuration {
@@ -88,11 +229,422 @@ impl Configuration {
             content = content.replace(&format!("${}", k), &v);
         }
 
-        let config: Configuration = match toml::from_str(&content) {
+        Configuration::from_str(&content)
+    }
+}
+
+impl FromStr for Configuration {
+    type Err = anyhow::Error;
+
+    fn from_str(content: &str) -> Result<Configuration> {
+        let mut config: Configuration = match toml::from_str(content) {
             Ok(v) => v,
             Err(err) => return Err(anyhow!("parse config file error: {}", err)),
         };
 
+        // In case no list of Concentratord instances has been configured, use the
+        // single Concentratord configuration.
+        if config.concentratords.is_empty() {
+            config.concentratords.push(config.concentratord.clone());
+        }
+
+        if config.concentratords.len() > 1 {
+            for (i, c) in config.concentratords.iter().enumerate() {
+                if c.name.is_empty() {
+                    return Err(anyhow!("concentratords must have a name"));
+                }
+
+                if config.concentratords[..i].iter().any(|v| v.name == c.name) {
+                    return Err(anyhow!("duplicate concentratord name: {}", c.name));
+                }
+            }
+        }
+
+        for server in &config.udp_forwarder.servers {
+            for name in &server.concentratords {
+                if !config.concentratords.iter().any(|v| &v.name == name) {
+                    return Err(anyhow!(
+                        "unknown concentratord: {}, server: {}",
+                        name,
+                        server.server
+                    ));
+                }
+            }
+
+            if server.protocol_version != PROTOCOL_VERSION_1
+                && server.protocol_version != PROTOCOL_VERSION_2
+            {
+                return Err(anyhow!(
+                    "unsupported protocol_version: {}, server: {}",
+                    server.protocol_version,
+                    server.server
+                ));
+            }
+
+            if let Err(e) = server.get_gateway_id() {
+                return Err(anyhow!("{}, server: {}", e, server.server));
+            }
+
+            for vg in &server.virtual_gateways {
+                if let Err(e) = decode_gateway_id(&vg.gateway_id) {
+                    return Err(anyhow!("virtual_gateways {}, server: {}", e, server.server));
+                }
+            }
+
+            if !server.gateway_id.is_empty() && !server.virtual_gateways.is_empty() {
+                return Err(anyhow!(
+                    "gateway_id can not be used in combination with virtual_gateways, server: {}",
+                    server.server
+                ));
+            }
+
+            let forwarders = config
+                .concentratords
+                .iter()
+                .filter(|v| server.forwards_concentratord(&v.name))
+                .count();
+
+            if forwarders > 1
+                && (!server.gateway_id.is_empty() || !server.virtual_gateways.is_empty())
+            {
+                return Err(anyhow!(
+                    "gateway_id and virtual_gateways can only be used when forwarding a single concentratord, server: {}",
+                    server.server
+                ));
+            }
+
+            // Each forwarder binds its own UDP socket(s), thus a fixed bind port can
+            // only be used when the server is forwarded by a single forwarder.
+            if forwarders * server.virtual_gateways.len().max(1) > 1
+                && (server.bind_port != 0 || server.bind_port_down != 0)
+            {
+                return Err(anyhow!(
+                    "bind_port and bind_port_down can only be used when forwarding a single concentratord or virtual gateway, server: {}",
+                    server.server
+                ));
+            }
+
+            if server.dscp > 63 {
+                return Err(anyhow!(
+                    "dscp must be between 0 and 63, server: {}",
+                    server.server
+                ));
+            }
+        }
+
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_concentratord() {
+        let config = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            bind_port=1700
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(1, config.concentratords.len());
+        assert_eq!("", config.concentratords[0].name);
+    }
+
+    #[test]
+    fn test_concentratord_names() {
+        let config = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[concentratords]]
+            name="card1"
+            [[concentratords]]
+            name="card2"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(2, config.concentratords.len());
+
+        // Duplicate name.
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[concentratords]]
+            name="card1"
+            [[concentratords]]
+            name="card1"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!("duplicate concentratord name: card1", err.to_string());
+
+        // Missing name.
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[concentratords]]
+            name="card1"
+            [[concentratords]]
+            event_url="ipc:///tmp/concentratord_event_2"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!("concentratords must have a name", err.to_string());
+    }
+
+    #[test]
+    fn test_unknown_concentratord() {
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            concentratords=["card2"]
+            [[concentratords]]
+            name="card1"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "unknown concentratord: card2, server: localhost:1700",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_bind_port_forwarders() {
+        for bind_port in ["bind_port", "bind_port_down"] {
+            let err = Configuration::from_str(&format!(
+                r#"
+                [udp_forwarder]
+                [[udp_forwarder.servers]]
+                server="localhost:1700"
+                {}=1700
+                [[concentratords]]
+                name="card1"
+                [[concentratords]]
+                name="card2"
+                "#,
+                bind_port
+            ))
+            .err()
+            .unwrap();
+            assert_eq!(
+                "bind_port and bind_port_down can only be used when forwarding a single concentratord or virtual gateway, server: localhost:1700",
+                err.to_string()
+            );
+
+            // Forwarding a single concentratord.
+            assert!(
+                Configuration::from_str(&format!(
+                    r#"
+                    [udp_forwarder]
+                    [[udp_forwarder.servers]]
+                    server="localhost:1700"
+                    {}=1700
+                    concentratords=["card2"]
+                    [[concentratords]]
+                    name="card1"
+                    [[concentratords]]
+                    name="card2"
+                    "#,
+                    bind_port
+                ))
+                .is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_gateway_id() {
+        assert_eq!(None, Server::default().get_gateway_id().unwrap());
+
+        let server = Server {
+            gateway_id: "0102030405060708".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 8]),
+            server.get_gateway_id().unwrap()
+        );
+
+        let server = Server {
+            gateway_id: "01020304050607".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            "gateway_id must be 8 bytes, got: 7",
+            server.get_gateway_id().err().unwrap().to_string()
+        );
+
+        let server = Server {
+            gateway_id: "010203040506070g".into(),
+            ..Default::default()
+        };
+        assert!(
+            server
+                .get_gateway_id()
+                .err()
+                .unwrap()
+                .to_string()
+                .starts_with("gateway_id decode error")
+        );
+    }
+
+    #[test]
+    fn test_gateway_id() {
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            gateway_id="0102"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "gateway_id must be 8 bytes, got: 2, server: localhost:1700",
+            err.to_string()
+        );
+
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            gateway_id="0102030405060708"
+            [[concentratords]]
+            name="card1"
+            [[concentratords]]
+            name="card2"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "gateway_id and virtual_gateways can only be used when forwarding a single concentratord, server: localhost:1700",
+            err.to_string()
+        );
+
+        // Forwarding a single concentratord.
+        assert!(
+            Configuration::from_str(
+                r#"
+                [udp_forwarder]
+                [[udp_forwarder.servers]]
+                server="localhost:1700"
+                gateway_id="0102030405060708"
+                concentratords=["card1"]
+                [[concentratords]]
+                name="card1"
+                [[concentratords]]
+                name="card2"
+                "#,
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_virtual_gateways() {
+        let config = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            [[udp_forwarder.servers.virtual_gateways]]
+            gateway_id="0102030405060701"
+            [[udp_forwarder.servers.virtual_gateways]]
+            gateway_id="0102030405060702"
+            antenna=1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(2, config.udp_forwarder.servers[0].virtual_gateways.len());
+
+        // Invalid gateway ID.
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            [[udp_forwarder.servers.virtual_gateways]]
+            gateway_id="01020304050607"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "virtual_gateways gateway_id must be 8 bytes, got: 7, server: localhost:1700",
+            err.to_string()
+        );
+
+        // Combined with gateway_id.
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            gateway_id="0102030405060708"
+            [[udp_forwarder.servers.virtual_gateways]]
+            gateway_id="0102030405060701"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "gateway_id can not be used in combination with virtual_gateways, server: localhost:1700",
+            err.to_string()
+        );
+
+        // Multiple concentratords.
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            [[udp_forwarder.servers.virtual_gateways]]
+            gateway_id="0102030405060701"
+            [[concentratords]]
+            name="card1"
+            [[concentratords]]
+            name="card2"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "gateway_id and virtual_gateways can only be used when forwarding a single concentratord, server: localhost:1700",
+            err.to_string()
+        );
+
+        // Fixed bind port with multiple virtual gateways.
+        let err = Configuration::from_str(
+            r#"
+            [udp_forwarder]
+            [[udp_forwarder.servers]]
+            server="localhost:1700"
+            bind_port=1700
+            [[udp_forwarder.servers.virtual_gateways]]
+            gateway_id="0102030405060701"
+            [[udp_forwarder.servers.virtual_gateways]]
+            gateway_id="0102030405060702"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "bind_port and bind_port_down can only be used when forwarding a single concentratord or virtual gateway, server: localhost:1700",
+            err.to_string()
+        );
+    }
+}
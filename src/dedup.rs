@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+// Previously received PULL_RESP.
+struct Item {
+    token: u16,
+    hash: u64,
+    received_at: Instant,
+    error: String,
+}
+
+// Window of recently received PULL_RESP tokens and payload hashes, used to detect
+// PULL_RESP retransmissions by the server and duplicates on the network. A
+// PULL_RESP is only a duplicate when both its token and payload match, so that
+// the server can deliberately send the same payload again using a new token.
+pub struct PullRespDedup {
+    ttl: Duration,
+    items: VecDeque<Item>,
+}
+
+impl PullRespDedup {
+    pub fn new(ttl: Duration) -> Self {
+        PullRespDedup {
+            ttl,
+            items: VecDeque::new(),
+        }
+    }
+
+    // Return the TX_ACK error of a previously received PULL_RESP with the same token
+    // and payload, or None in case it was not received within the TTL.
+    pub fn get_duplicate(&mut self, token: u16, payload: &[u8]) -> Option<String> {
+        let ttl = self.ttl;
+        self.items.retain(|v| v.received_at.elapsed() < ttl);
+
+        let hash = hash(payload);
+        self.items
+            .iter()
+            .find(|v| v.token == token && v.hash == hash)
+            .map(|v| v.error.clone())
+    }
+
+    // Add the PULL_RESP with the TX_ACK error that was returned for it.
+    pub fn add(&mut self, token: u16, payload: &[u8], error: String) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        self.items.push_back(Item {
+            token,
+            hash: hash(payload),
+            received_at: Instant::now(),
+            error,
+        });
+    }
+}
+
+fn hash(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_pull_resp_dedup() {
+        let mut d = PullRespDedup::new(Duration::from_secs(60));
+
+        assert_eq!(None, d.get_duplicate(1, &[1, 2, 3]));
+        d.add(1, &[1, 2, 3], "TOO_LATE".into());
+
+        // Same token and payload.
+        assert_eq!(Some("TOO_LATE".into()), d.get_duplicate(1, &[1, 2, 3]));
+
+        // Same payload, different token.
+        assert_eq!(None, d.get_duplicate(2, &[1, 2, 3]));
+
+        // Same token, different payload.
+        assert_eq!(None, d.get_duplicate(1, &[1, 2, 4]));
+    }
+
+    #[test]
+    fn test_pull_resp_dedup_ttl() {
+        let mut d = PullRespDedup::new(Duration::from_millis(10));
+        d.add(1, &[1, 2, 3], "".into());
+        assert_eq!(Some("".into()), d.get_duplicate(1, &[1, 2, 3]));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(None, d.get_duplicate(1, &[1, 2, 3]));
+        assert!(d.items.is_empty());
+    }
+
+    #[test]
+    fn test_pull_resp_dedup_disabled() {
+        let mut d = PullRespDedup::new(Duration::ZERO);
+        d.add(1, &[1, 2, 3], "".into());
+        assert_eq!(None, d.get_duplicate(1, &[1, 2, 3]));
+    }
+}
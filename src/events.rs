@@ -1,10 +1,16 @@
-use std::time::Duration;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError, channel, sync_channel};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use chirpstack_api::{gw, prost::Message};
 
+use super::metrics;
 use super::socket::ZMQ_CONTEXT;
 
+// Max. number of events queued per forwarder. Events are dropped in case the
+// forwarder falls behind.
+const EVENT_CHANNEL_SIZE: usize = 1000;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Timeout")]
@@ -57,3 +63,164 @@ impl Iterator for Reader<'_> {
         }
     }
 }
+
+// Event queue of a forwarder. The dropping flag is set while events are being
+// dropped, so that this is logged only once when it starts and when it recovers.
+struct Queue {
+    server: String,
+    sender: SyncSender<gw::Event>,
+    dropping: bool,
+}
+
+// Dispatcher reads and decodes the Concentratord events once and fans these out
+// to the forwarders.
+pub struct Dispatcher {
+    queues: Vec<Queue>,
+    resume: Option<(Duration, Sender<()>)>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Dispatcher {
+            queues: vec![],
+            resume: None,
+        }
+    }
+
+    // Returns a receiver which is notified when the event stream resumes after a gap
+    // of at least the given duration. A zero duration disables the notification.
+    pub fn new_resume_receiver(&mut self, gap: Duration) -> Receiver<()> {
+        let (sender, receiver) = channel();
+        self.resume = Some((gap, sender));
+        receiver
+    }
+
+    pub fn new_receiver(&mut self, server: &str) -> Receiver<gw::Event> {
+        let (sender, receiver) = sync_channel(EVENT_CHANNEL_SIZE);
+        self.queues.push(Queue {
+            server: server.to_string(),
+            sender,
+            dropping: false,
+        });
+        receiver
+    }
+
+    pub fn start(mut self, endpoint: String) {
+        let sock = get_socket(&endpoint).expect("get events client error");
+        let reader = Reader::new(&sock, Duration::from_millis(100));
+        let mut last_event = Instant::now();
+
+        for event in reader {
+            let event = match event {
+                Ok(v) => v,
+                Err(Error::Timeout) => continue,
+                Err(e) => {
+                    warn!("Read event error, error: {}", e);
+                    continue;
+                }
+            };
+
+            if let Some((gap, sender)) = &self.resume {
+                if !gap.is_zero() && last_event.elapsed() >= *gap {
+                    info!(
+                        "Concentratord events resumed after gap, gap: {:?}",
+                        last_event.elapsed()
+                    );
+                    let _ = sender.send(());
+                }
+            }
+            last_event = Instant::now();
+
+            self.dispatch(event);
+        }
+    }
+
+    // Send the event to each receiver. In case the queue of a receiver is full, the
+    // event is dropped for that receiver.
+    fn dispatch(&mut self, event: gw::Event) {
+        for queue in &mut self.queues {
+            match queue.sender.try_send(event.clone()) {
+                Ok(_) => {
+                    if queue.dropping {
+                        info!(
+                            "Event queue recovered, forwarding events, server: {}",
+                            queue.server
+                        );
+                        queue.dropping = false;
+                    }
+                }
+                Err(TrySendError::Full(_)) => {
+                    if !queue.dropping {
+                        warn!(
+                            "Event queue is full, dropping events, server: {}",
+                            queue.server
+                        );
+                        queue.dropping = true;
+                    }
+                    metrics::incr_events_dropped_count(&queue.server);
+                }
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> gw::Event {
+        gw::Event {
+            event: Some(gw::event::Event::GatewayStats(gw::GatewayStats::default())),
+        }
+    }
+
+    #[test]
+    fn test_dispatcher_dispatch() {
+        let mut d = Dispatcher::new();
+        let r1 = d.new_receiver("dispatch_a");
+        let r2 = d.new_receiver("dispatch_b");
+
+        d.dispatch(event());
+
+        assert_eq!(event(), r1.try_recv().unwrap());
+        assert_eq!(event(), r2.try_recv().unwrap());
+        assert!(r1.try_recv().is_err());
+        assert!(r2.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_dispatcher_dispatch_full() {
+        let mut d = Dispatcher::new();
+        let r1 = d.new_receiver("dispatch_full_a");
+        let r2 = d.new_receiver("dispatch_full_b");
+
+        // Fill the queue of the first receiver, while draining the second.
+        for _ in 0..EVENT_CHANNEL_SIZE {
+            d.dispatch(event());
+            r2.try_recv().unwrap();
+        }
+
+        // The events are dropped for the first receiver only.
+        d.dispatch(event());
+        d.dispatch(event());
+        assert!(d.queues[0].dropping);
+        assert!(!d.queues[1].dropping);
+        assert_eq!(2, r2.try_iter().count());
+        assert_eq!(EVENT_CHANNEL_SIZE, r1.try_iter().count());
+        assert_eq!(2, metrics::get_events_dropped_count("dispatch_full_a"));
+        assert_eq!(0, metrics::get_events_dropped_count("dispatch_full_b"));
+
+        // The queue recovers once the receiver has caught up.
+        d.dispatch(event());
+        assert!(!d.queues[0].dropping);
+        assert_eq!(event(), r1.try_recv().unwrap());
+        assert_eq!(event(), r2.try_recv().unwrap());
+
+        // A dropped receiver does not affect the others.
+        drop(r1);
+        d.dispatch(event());
+        assert_eq!(event(), r2.try_recv().unwrap());
+        assert_eq!(2, metrics::get_events_dropped_count("dispatch_full_a"));
+    }
+}
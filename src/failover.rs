@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::config::Server;
+
+struct MemberState {
+    server: String,
+    hold_down: Duration,
+    failed: bool,
+    healthy_since: Option<Instant>,
+}
+
+struct GroupState {
+    members: Vec<MemberState>,
+    active: usize,
+}
+
+// Failover group of servers. Only the active server of the group receives the
+// uplinks. The order of the servers within the configuration defines the priority,
+// the first server being the primary.
+pub struct Group {
+    name: String,
+    state: Mutex<GroupState>,
+}
+
+impl Group {
+    fn new(name: &str) -> Self {
+        Group {
+            name: name.to_string(),
+            state: Mutex::new(GroupState {
+                members: vec![],
+                active: 0,
+            }),
+        }
+    }
+
+    fn add_member(&self, server: &str, hold_down: Duration) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.members.push(MemberState {
+            server: server.to_string(),
+            hold_down,
+            failed: false,
+            healthy_since: None,
+        });
+        state.members.len() - 1
+    }
+
+    fn is_active(&self, index: usize) -> bool {
+        self.state.lock().unwrap().active == index
+    }
+
+    fn set_healthy(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        let active = state.active;
+        let active_failed = state.members[active].failed;
+
+        let member = &mut state.members[index];
+        member.failed = false;
+        let healthy_since = *member.healthy_since.get_or_insert_with(Instant::now);
+
+        // Switch in case the active server has failed, or in case this server has a
+        // higher priority and has been healthy for the hold-down period.
+        if index != active
+            && (active_failed || (index < active && healthy_since.elapsed() >= member.hold_down))
+        {
+            info!(
+                "Switching active server, failover_group: {}, from: {}, to: {}",
+                self.name, state.members[active].server, state.members[index].server
+            );
+            state.active = index;
+        }
+    }
+
+    fn set_unhealthy(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.members[index].healthy_since = None;
+    }
+
+    fn set_failed(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.members[index].failed = true;
+        state.members[index].healthy_since = None;
+
+        if state.active != index {
+            return;
+        }
+
+        let len = state.members.len();
+        match (1..len)
+            .map(|i| (index + i) % len)
+            .find(|i| !state.members[*i].failed)
+        {
+            Some(i) => {
+                warn!(
+                    "Switching active server, failover_group: {}, from: {}, to: {}",
+                    self.name, state.members[index].server, state.members[i].server
+                );
+                state.active = i;
+            }
+            None => {
+                warn!(
+                    "No healthy server available, failover_group: {}, active: {}",
+                    self.name, state.members[index].server
+                );
+            }
+        }
+    }
+}
+
+// Membership of a server within a failover group.
+#[derive(Clone)]
+pub struct Member {
+    group: Arc<Group>,
+    index: usize,
+}
+
+impl Member {
+    // Returns true if the server is the active server of its failover group.
+    pub fn is_active(&self) -> bool {
+        self.group.is_active(self.index)
+    }
+
+    // Mark the server as healthy, e.g. after receiving a PULL_ACK.
+    pub fn set_healthy(&self) {
+        self.group.set_healthy(self.index)
+    }
+
+    // Reset the hold-down period of the server without failing over, e.g. after a
+    // missed PULL_ACK.
+    pub fn set_unhealthy(&self) {
+        self.group.set_unhealthy(self.index)
+    }
+
+    // Mark the server as failed, e.g. after reaching the max. keepalive failures.
+    pub fn set_failed(&self) {
+        self.group.set_failed(self.index)
+    }
+}
+
+// Returns the failover group membership for each of the given servers, or None in
+// case the server is not part of a failover group.
+pub fn get_members<'a>(servers: impl IntoIterator<Item = &'a Server>) -> Vec<Option<Member>> {
+    let mut groups: HashMap<String, Arc<Group>> = HashMap::new();
+
+    servers
+        .into_iter()
+        .map(|s| {
+            if s.failover_group.is_empty() {
+                return None;
+            }
+
+            let group = groups
+                .entry(s.failover_group.clone())
+                .or_insert_with(|| Arc::new(Group::new(&s.failover_group)))
+                .clone();
+            let index = group.add_member(&s.server, Duration::from_secs(s.failover_hold_down_secs));
+
+            Some(Member { group, index })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_members() {
+        let servers = vec![
+            Server {
+                server: "primary:1700".into(),
+                failover_group: "a".into(),
+                ..Default::default()
+            },
+            Server {
+                server: "other:1700".into(),
+                ..Default::default()
+            },
+            Server {
+                server: "backup:1700".into(),
+                failover_group: "a".into(),
+                ..Default::default()
+            },
+        ];
+
+        let members = get_members(&servers);
+        assert!(members[0].as_ref().unwrap().is_active());
+        assert!(members[1].is_none());
+        assert!(!members[2].as_ref().unwrap().is_active());
+        assert_eq!(1, members[2].as_ref().unwrap().index);
+    }
+
+    #[test]
+    fn test_failover() {
+        let group = Arc::new(Group::new("a"));
+        let primary = Member {
+            index: group.add_member("primary:1700", Duration::from_secs(0)),
+            group: group.clone(),
+        };
+        let backup = Member {
+            index: group.add_member("backup:1700", Duration::from_secs(0)),
+            group: group.clone(),
+        };
+
+        assert!(primary.is_active());
+
+        // Switch to backup on failure.
+        primary.set_failed();
+        assert!(backup.is_active());
+
+        // Stay on backup in case it fails too, as there is no healthy server.
+        backup.set_failed();
+        assert!(backup.is_active());
+
+        // Switch to primary as the active server has failed.
+        primary.set_healthy();
+        assert!(primary.is_active());
+
+        // The backup does not take over once healthy.
+        backup.set_healthy();
+        assert!(primary.is_active());
+
+        // Switch back to primary after the hold-down (0s).
+        primary.set_failed();
+        assert!(backup.is_active());
+        primary.set_healthy();
+        assert!(primary.is_active());
+    }
+
+    #[test]
+    fn test_failover_hold_down() {
+        let group = Arc::new(Group::new("a"));
+        let primary = Member {
+            index: group.add_member("primary:1700", Duration::from_secs(60)),
+            group: group.clone(),
+        };
+        let backup = Member {
+            index: group.add_member("backup:1700", Duration::from_secs(60)),
+            group: group.clone(),
+        };
+
+        primary.set_failed();
+        assert!(backup.is_active());
+
+        // The primary must be healthy for the hold-down period first.
+        primary.set_healthy();
+        backup.set_healthy();
+        assert!(backup.is_active());
+    }
+    #[test]
+    fn test_failover_unhealthy() {
+        let group = Arc::new(Group::new("a"));
+        let primary = Member {
+            index: group.add_member("primary:1700", Duration::from_millis(50)),
+            group: group.clone(),
+        };
+        let backup = Member {
+            index: group.add_member("backup:1700", Duration::from_millis(50)),
+            group: group.clone(),
+        };
+
+        primary.set_failed();
+        assert!(backup.is_active());
+        primary.set_healthy();
+
+        // A missed PULL_ACK does not fail over, but restarts the hold-down period.
+        std::thread::sleep(Duration::from_millis(60));
+        primary.set_unhealthy();
+        assert!(backup.is_active());
+        primary.set_healthy();
+        assert!(backup.is_active());
+
+        backup.set_unhealthy();
+        assert!(backup.is_active());
+
+        std::thread::sleep(Duration::from_millis(60));
+        primary.set_healthy();
+        assert!(primary.is_active());
+    }
+}
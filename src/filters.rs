@@ -0,0 +1,525 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result};
+use chirpstack_api::gw;
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer};
+
+use super::config::{Server, VirtualGateway};
+use super::structs::Modulation;
+
+// DevAddr prefix, e.g. 0000ff00/24.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DevAddrPrefix {
+    prefix: u32,
+    size: u32,
+}
+
+impl DevAddrPrefix {
+    fn mask(&self) -> u32 {
+        match self.size {
+            0 => 0,
+            _ => u32::MAX << (32 - self.size),
+        }
+    }
+
+    pub fn matches(&self, dev_addr: u32) -> bool {
+        dev_addr & self.mask() == self.prefix & self.mask()
+    }
+}
+
+impl FromStr for DevAddrPrefix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (prefix, size) = match s.split_once('/') {
+            Some(v) => v,
+            None => return Err(anyhow!("expected format: 0000ff00/24, got: {}", s)),
+        };
+
+        let prefix = u32::from_str_radix(prefix, 16)?;
+        let size: u32 = size.parse()?;
+        if size > 32 {
+            return Err(anyhow!("prefix size must not exceed 32, got: {}", size));
+        }
+
+        Ok(DevAddrPrefix { prefix, size })
+    }
+}
+
+impl<'de> Deserialize<'de> for DevAddrPrefix {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        DevAddrPrefix::from_str(&s).map_err(D::Error::custom)
+    }
+}
+
+// NetID, e.g. 000013.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NetId(u32);
+
+impl NetId {
+    // Returns the DevAddr prefix of the NetID (see LoRaWAN Backend Interfaces).
+    pub fn dev_addr_prefix(&self) -> DevAddrPrefix {
+        let net_id_type = self.0 >> 21;
+        let nwk_id_size = match net_id_type {
+            0 | 1 => 6,
+            2 => 9,
+            3 => 11,
+            4 => 12,
+            5 => 13,
+            6 => 15,
+            _ => 17,
+        };
+        let nwk_id = self.0 & ((1 << nwk_id_size) - 1);
+
+        // The type prefix is formed by net_id_type 1 bits, followed by a 0 bit.
+        let type_size = net_id_type + 1;
+        let type_prefix = (0xff_u32 << (8 - net_id_type)) & 0xff;
+        let size = type_size + nwk_id_size;
+
+        DevAddrPrefix {
+            prefix: (type_prefix << 24) | (nwk_id << (32 - size)),
+            size,
+        }
+    }
+}
+
+impl FromStr for NetId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 6 {
+            return Err(anyhow!("expected 3 bytes hex encoded NetID, got: {}", s));
+        }
+
+        Ok(NetId(u32::from_str_radix(s, 16)?))
+    }
+}
+
+impl<'de> Deserialize<'de> for NetId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        NetId::from_str(&s).map_err(D::Error::custom)
+    }
+}
+
+// EUI64 range (inclusive), e.g. 0000000000000000-00000000000000ff.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EuiRange {
+    start: u64,
+    end: u64,
+}
+
+impl EuiRange {
+    pub fn matches(&self, eui: u64) -> bool {
+        eui >= self.start && eui <= self.end
+    }
+}
+
+impl FromStr for EuiRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = match s.split_once('-') {
+            Some(v) => v,
+            None => {
+                return Err(anyhow!(
+                    "expected format: 0000000000000000-00000000000000ff, got: {}",
+                    s
+                ));
+            }
+        };
+
+        let start = u64::from_str_radix(start, 16)?;
+        let end = u64::from_str_radix(end, 16)?;
+        if start > end {
+            return Err(anyhow!("range start must not exceed end, got: {}", s));
+        }
+
+        Ok(EuiRange { start, end })
+    }
+}
+
+impl<'de> Deserialize<'de> for EuiRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        EuiRange::from_str(&s).map_err(D::Error::custom)
+    }
+}
+
+// Uplink filters based on the radio meta-data and the LoRaWAN MHDR and FHDR.
+pub struct Filters {
+    frequency_min: u32,
+    frequency_max: u32,
+    modulations: Vec<Modulation>,
+    spreading_factors: Vec<u32>,
+    bandwidths: Vec<u32>,
+    min_rssi: Option<i32>,
+    min_snr: Option<f32>,
+    allow_dev_addr_prefixes: Vec<DevAddrPrefix>,
+    deny_dev_addr_prefixes: Vec<DevAddrPrefix>,
+    allow_join_eui_ranges: Vec<EuiRange>,
+    deny_join_eui_ranges: Vec<EuiRange>,
+}
+
+impl Filters {
+    pub fn from_config(conf: &Server) -> Self {
+        Filters {
+            frequency_min: conf.frequency_min,
+            frequency_max: conf.frequency_max,
+            modulations: conf.modulations.clone(),
+            spreading_factors: conf.spreading_factors.clone(),
+            bandwidths: conf.bandwidths.clone(),
+            min_rssi: conf.min_rssi,
+            min_snr: conf.min_snr,
+            allow_dev_addr_prefixes: conf
+                .allow_dev_addr_prefixes
+                .iter()
+                .cloned()
+                .chain(conf.allow_net_ids.iter().map(|v| v.dev_addr_prefix()))
+                .collect(),
+            deny_dev_addr_prefixes: conf
+                .deny_dev_addr_prefixes
+                .iter()
+                .cloned()
+                .chain(conf.deny_net_ids.iter().map(|v| v.dev_addr_prefix()))
+                .collect(),
+            allow_join_eui_ranges: conf.allow_join_eui_ranges.clone(),
+            deny_join_eui_ranges: conf.deny_join_eui_ranges.clone(),
+        }
+    }
+
+    // Returns true if the given uplink must be forwarded.
+    pub fn matches(&self, up: &gw::UplinkFrame) -> bool {
+        self.matches_radio(up) && self.matches_phy_payload(&up.phy_payload)
+    }
+
+    fn matches_radio(&self, up: &gw::UplinkFrame) -> bool {
+        if let Some(tx_info) = &up.tx_info {
+            if (self.frequency_min != 0 && tx_info.frequency < self.frequency_min)
+                || (self.frequency_max != 0 && tx_info.frequency > self.frequency_max)
+            {
+                return false;
+            }
+
+            let parameters = tx_info
+                .modulation
+                .as_ref()
+                .and_then(|v| v.parameters.as_ref());
+
+            if !self.modulations.is_empty() {
+                let modulation = match parameters {
+                    Some(gw::modulation::Parameters::Lora(_)) => Modulation::Lora,
+                    Some(gw::modulation::Parameters::Fsk(_)) => Modulation::Fsk,
+                    Some(gw::modulation::Parameters::LrFhss(_)) => Modulation::LrFhss,
+                    None => return false,
+                };
+
+                if !self.modulations.contains(&modulation) {
+                    return false;
+                }
+            }
+
+            // The SF and BW filters only apply to LoRa modulation.
+            if let Some(gw::modulation::Parameters::Lora(v)) = parameters {
+                if (!self.spreading_factors.is_empty()
+                    && !self.spreading_factors.contains(&v.spreading_factor))
+                    || (!self.bandwidths.is_empty() && !self.bandwidths.contains(&v.bandwidth))
+                {
+                    return false;
+                }
+            }
+        }
+
+        if let Some(rx_info) = &up.rx_info {
+            if let Some(min_rssi) = self.min_rssi {
+                if rx_info.rssi < min_rssi {
+                    return false;
+                }
+            }
+
+            // The SNR filter only applies to LoRa and LR-FHSS modulation, as no SNR
+            // is reported for FSK uplinks.
+            let parameters = up
+                .tx_info
+                .as_ref()
+                .and_then(|v| v.modulation.as_ref())
+                .and_then(|v| v.parameters.as_ref());
+            if let (
+                Some(min_snr),
+                Some(gw::modulation::Parameters::Lora(_))
+                | Some(gw::modulation::Parameters::LrFhss(_)),
+            ) = (self.min_snr, parameters)
+            {
+                if rx_info.snr < min_snr {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    // Frames that can not be parsed and proprietary frames are always forwarded.
+    fn matches_phy_payload(&self, phy_payload: &[u8]) -> bool {
+        if phy_payload.is_empty() {
+            return true;
+        }
+
+        match phy_payload[0] >> 5 {
+            // Join-request
+            0x00 => match phy_payload.get(1..9) {
+                Some(b) => self.matches_join_eui(u64::from_le_bytes(b.try_into().unwrap())),
+                None => true,
+            },
+            // Unconfirmed and confirmed data-up
+            0x02 | 0x04 => match phy_payload.get(1..5) {
+                Some(b) => self.matches_dev_addr(u32::from_le_bytes(b.try_into().unwrap())),
+                None => true,
+            },
+            // Rejoin-request type 1 (contains the JoinEUI)
+            0x06 if phy_payload.get(1) == Some(&0x01) => match phy_payload.get(2..10) {
+                Some(b) => self.matches_join_eui(u64::from_le_bytes(b.try_into().unwrap())),
+                None => true,
+            },
+            _ => true,
+        }
+    }
+
+    fn matches_dev_addr(&self, dev_addr: u32) -> bool {
+        if self
+            .deny_dev_addr_prefixes
+            .iter()
+            .any(|v| v.matches(dev_addr))
+        {
+            return false;
+        }
+
+        self.allow_dev_addr_prefixes.is_empty()
+            || self
+                .allow_dev_addr_prefixes
+                .iter()
+                .any(|v| v.matches(dev_addr))
+    }
+
+    fn matches_join_eui(&self, join_eui: u64) -> bool {
+        if self
+            .deny_join_eui_ranges
+            .iter()
+            .any(|v| v.matches(join_eui))
+        {
+            return false;
+        }
+
+        self.allow_join_eui_ranges.is_empty()
+            || self
+                .allow_join_eui_ranges
+                .iter()
+                .any(|v| v.matches(join_eui))
+    }
+}
+
+// Returns true if the uplink was received by the board, antenna and (optionally)
+// RF chain mapped to the virtual gateway.
+pub fn matches_virtual_gateway(vg: &VirtualGateway, up: &gw::UplinkFrame) -> bool {
+    match &up.rx_info {
+        Some(rx_info) => {
+            rx_info.board == vg.board
+                && rx_info.antenna == vg.antenna
+                && vg.rf_chain.is_none_or(|v| v == rx_info.rf_chain)
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_net_id_dev_addr_prefix() {
+        let tests = vec![
+            ("000013", "26000000/7"),
+            ("600010", "e0200000/15"),
+            ("c00050", "fc014000/22"),
+            ("e0003f", "fe001f80/25"),
+        ];
+
+        for (net_id, prefix) in tests {
+            assert_eq!(
+                DevAddrPrefix::from_str(prefix).unwrap(),
+                NetId::from_str(net_id).unwrap().dev_addr_prefix(),
+                "net_id: {}",
+                net_id
+            );
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let conf = Server {
+            allow_dev_addr_prefixes: vec![DevAddrPrefix::from_str("01000000/8").unwrap()],
+            deny_dev_addr_prefixes: vec![DevAddrPrefix::from_str("01020000/16").unwrap()],
+            allow_net_ids: vec![NetId::from_str("000013").unwrap()],
+            allow_join_eui_ranges: vec![
+                EuiRange::from_str("0000000000000000-00000000000000ff").unwrap(),
+            ],
+            deny_join_eui_ranges: vec![
+                EuiRange::from_str("0000000000000010-000000000000001f").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let f = Filters::from_config(&conf);
+
+        // Data-up, DevAddr 01ff0000.
+        assert!(f.matches_phy_payload(&[0x40, 0x00, 0x00, 0xff, 0x01, 0x00]));
+        // Data-up, DevAddr 01020304 (denied).
+        assert!(!f.matches_phy_payload(&[0x80, 0x04, 0x03, 0x02, 0x01, 0x00]));
+        // Data-up, DevAddr 26011234 (allowed NetID 000013).
+        assert!(f.matches_phy_payload(&[0x40, 0x34, 0x12, 0x01, 0x26, 0x00]));
+        // Data-up, DevAddr 02000000 (not allowed).
+        assert!(!f.matches_phy_payload(&[0x40, 0x00, 0x00, 0x00, 0x02, 0x00]));
+
+        // Join-request, JoinEUI 0000000000000001.
+        assert!(f.matches_phy_payload(&[0x00, 0x01, 0, 0, 0, 0, 0, 0, 0]));
+        // Join-request, JoinEUI 0000000000000011 (denied).
+        assert!(!f.matches_phy_payload(&[0x00, 0x11, 0, 0, 0, 0, 0, 0, 0]));
+        // Join-request, JoinEUI 0100000000000000 (not allowed).
+        assert!(!f.matches_phy_payload(&[0x00, 0, 0, 0, 0, 0, 0, 0, 0x01]));
+
+        // Proprietary.
+        assert!(f.matches_phy_payload(&[0xe0, 0x01]));
+    }
+
+    #[test]
+    fn test_filters_radio() {
+        let conf = Server {
+            frequency_min: 867000000,
+            frequency_max: 868000000,
+            modulations: vec![Modulation::Lora],
+            spreading_factors: vec![7, 8, 9],
+            bandwidths: vec![125000],
+            min_rssi: Some(-120),
+            min_snr: Some(-10.0),
+            ..Default::default()
+        };
+        let f = Filters::from_config(&conf);
+
+        let up = |frequency: u32, sf: u32, rssi: i32, snr: f32| gw::UplinkFrame {
+            tx_info: Some(gw::UplinkTxInfo {
+                frequency,
+                modulation: Some(gw::Modulation {
+                    parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                        bandwidth: 125000,
+                        spreading_factor: sf,
+                        ..Default::default()
+                    })),
+                }),
+            }),
+            rx_info: Some(gw::UplinkRxInfo {
+                rssi,
+                snr,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert!(f.matches(&up(867100000, 7, -100, 5.0)));
+        assert!(!f.matches(&up(868100000, 7, -100, 5.0)));
+        assert!(!f.matches(&up(866900000, 7, -100, 5.0)));
+        assert!(!f.matches(&up(867100000, 10, -100, 5.0)));
+        assert!(!f.matches(&up(867100000, 7, -121, 5.0)));
+        assert!(!f.matches(&up(867100000, 7, -100, -10.5)));
+
+        let fsk = gw::UplinkFrame {
+            tx_info: Some(gw::UplinkTxInfo {
+                frequency: 867100000,
+                modulation: Some(gw::Modulation {
+                    parameters: Some(gw::modulation::Parameters::Fsk(Default::default())),
+                }),
+            }),
+            ..Default::default()
+        };
+        assert!(!f.matches(&fsk));
+    }
+
+    #[test]
+    fn test_filters_min_snr() {
+        let conf = Server {
+            min_snr: Some(-10.0),
+            ..Default::default()
+        };
+        let f = Filters::from_config(&conf);
+
+        let up = |parameters: gw::modulation::Parameters, snr: f32| gw::UplinkFrame {
+            tx_info: Some(gw::UplinkTxInfo {
+                frequency: 867100000,
+                modulation: Some(gw::Modulation {
+                    parameters: Some(parameters),
+                }),
+            }),
+            rx_info: Some(gw::UplinkRxInfo {
+                snr,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let lora = || gw::modulation::Parameters::Lora(Default::default());
+        let lr_fhss = || gw::modulation::Parameters::LrFhss(Default::default());
+        let fsk = || gw::modulation::Parameters::Fsk(Default::default());
+
+        assert!(f.matches(&up(lora(), -5.0)));
+        assert!(!f.matches(&up(lora(), -10.5)));
+        assert!(!f.matches(&up(lr_fhss(), -10.5)));
+
+        // FSK uplinks are forwarded regardless of the SNR.
+        let f = Filters::from_config(&Server {
+            min_snr: Some(5.0),
+            ..Default::default()
+        });
+        assert!(f.matches(&up(fsk(), 0.0)));
+        assert!(!f.matches(&up(lora(), 0.0)));
+    }
+
+    #[test]
+    fn test_matches_virtual_gateway() {
+        let up = |board: u32, antenna: u32, rf_chain: u32| gw::UplinkFrame {
+            rx_info: Some(gw::UplinkRxInfo {
+                board,
+                antenna,
+                rf_chain,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let vg = VirtualGateway {
+            board: 1,
+            antenna: 2,
+            ..Default::default()
+        };
+        assert!(matches_virtual_gateway(&vg, &up(1, 2, 0)));
+        assert!(matches_virtual_gateway(&vg, &up(1, 2, 1)));
+        assert!(!matches_virtual_gateway(&vg, &up(0, 2, 0)));
+        assert!(!matches_virtual_gateway(&vg, &up(1, 0, 0)));
+
+        let vg = VirtualGateway {
+            board: 1,
+            antenna: 2,
+            rf_chain: Some(1),
+            ..Default::default()
+        };
+        assert!(matches_virtual_gateway(&vg, &up(1, 2, 1)));
+        assert!(!matches_virtual_gateway(&vg, &up(1, 2, 0)));
+    }
+}
@@ -1,39 +1,75 @@
-use std::net::UdpSocket;
-use std::sync::mpsc::Receiver;
-use std::sync::{Arc, Mutex};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
 use std::{thread, time};
 
 use anyhow::Result;
-use chirpstack_api::{gw, prost::Message};
+use chirpstack_api::gw;
 use rand::Rng;
 
+use super::aggregation::PendingPushData;
+use super::backoff::Backoff;
+use super::buffer::Buffer;
 use super::commands;
-use super::config::Server;
-use super::events;
+use super::config::{Concentratord, DownlinkMode, Server, VirtualGateway};
+use super::dedup::PullRespDedup;
+use super::failover;
+use super::filters::{self, Filters};
 use super::metrics;
 use super::signals;
 use super::structs;
+use super::tokens::PushDataTokens;
+use super::udp;
+
+// Min. number of in-flight PUSH_DATA tokens which are tracked for matching the
+// PUSH_ACK responses. When the buffer is enabled, the window is sized to the
+// buffer size as each re-sent datagram is in-flight again.
+const PUSH_DATA_TOKEN_WINDOW_MIN: usize = 64;
+
+// Min. and max. delay between attempts to setup the UDP socket(s), e.g. in case
+// the server hostname could not be resolved.
+const RECONNECT_BACKOFF_MIN: time::Duration = time::Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: time::Duration = time::Duration::from_secs(60);
 
 struct State {
     server: String,
+    protocol_version: u8,
     keepalive_interval: time::Duration,
     forward_crc_ok: bool,
     forward_crc_invalid: bool,
     forward_crc_missing: bool,
+    filters: Filters,
     keepalive_max_failures: u32,
+    downlink_mode: DownlinkMode,
+    failover_member: Option<failover::Member>,
+    virtual_gateway: Option<VirtualGateway>,
+    stat_counters: bool,
+    aggregation_interval: time::Duration,
+    max_push_data_size: usize,
+    retransmit_batch_size: usize,
     gateway_id: Vec<u8>,
-    socket: UdpSocket,
-    push_data_token: Mutex<u16>,
-    push_data_sent: Mutex<u32>,
-    push_data_acked: Mutex<u32>,
+    upstream_gateway_id: Vec<u8>,
+    current_gateway_id: Arc<RwLock<Vec<u8>>>,
+    socket_up: UdpSocket,
+    socket_down: Option<UdpSocket>,
+    push_data_tokens: Mutex<PushDataTokens>,
     pull_data_token: Mutex<u16>,
     pull_data_token_acked: Mutex<u16>,
     rxfw: Mutex<u32>,
-    event_sock: Mutex<zmq::Socket>,
-    command_sock: Mutex<zmq::Socket>,
+    pending_push_data: Mutex<PendingPushData>,
+    pull_resp_dedup: Mutex<PullRespDedup>,
+    buffer: Arc<Mutex<Buffer>>,
+    retransmit_pending: Mutex<bool>,
+    events: Arc<Mutex<Receiver<gw::Event>>>,
+    command_client: Arc<Mutex<commands::Client>>,
 }
 
 impl State {
+    // Returns the socket used for PULL_DATA, PULL_RESP and TX_ACK frames.
+    fn get_socket_down(&self) -> &UdpSocket {
+        self.socket_down.as_ref().unwrap_or(&self.socket_up)
+    }
+
     fn set_pull_data_token(&self) -> u16 {
         let mut rng = rand::rng();
         let mut token = self.pull_data_token.lock().unwrap();
@@ -55,43 +91,14 @@ impl State {
     }
 
     fn set_push_data_token(&self) -> u16 {
-        let mut rng = rand::rng();
-        let mut token = self.push_data_token.lock().unwrap();
-        *token = rng.random();
-        *token
-    }
-
-    fn get_push_data_token(&self) -> u16 {
-        return *self.push_data_token.lock().unwrap();
-    }
-
-    fn incr_push_data_sent(&self) {
-        let mut sent = self.push_data_sent.lock().unwrap();
-        *sent += 1;
-    }
-
-    fn get_and_reset_push_data_sent(&self) -> u32 {
-        let mut sent = self.push_data_sent.lock().unwrap();
-        let out = *sent;
-        *sent = 0;
-        out
-    }
-
-    fn incr_push_data_acked(&self) {
-        let mut acked = self.push_data_acked.lock().unwrap();
-        *acked += 1;
+        let buffer = self.buffer.lock().unwrap();
+        let mut tokens = self.push_data_tokens.lock().unwrap();
+        tokens.next(|v| buffer.contains(v))
     }
 
-    fn get_and_reset_push_data_acked(&self) -> u32 {
-        let mut acked = self.push_data_acked.lock().unwrap();
-        let out = *acked;
-        *acked = 0;
-        out
-    }
-
-    fn incr_rxfw(&self) {
+    fn incr_rxfw(&self, count: u32) {
         let mut rxfw = self.rxfw.lock().unwrap();
-        *rxfw += 1;
+        *rxfw += count;
     }
 
     fn get_and_reset_rxfw(&self) -> u32 {
@@ -102,24 +109,89 @@ impl State {
     }
 }
 
-pub fn start(conf: &Server, event_url: String, command_url: String, gateway_id: Vec<u8>) {
+// Start the forwarder for the given server. The name identifies the forwarder in
+// the logs and metrics, as multiple forwarders can be started for the same server.
+pub fn start(
+    name: &str,
+    conf: &Server,
+    events: Receiver<gw::Event>,
+    concentratord: &Concentratord,
+    gateway_id: Arc<RwLock<Vec<u8>>>,
+    failover_member: Option<failover::Member>,
+    virtual_gateway: Option<VirtualGateway>,
+) {
+    // The buffer is shared between restarts of the forwarder, as these are
+    // typically caused by a backhaul outage.
+    let buffer = Arc::new(Mutex::new(Buffer::new(
+        conf.buffer_size,
+        time::Duration::from_secs(conf.buffer_max_age_secs),
+        &conf.buffer_path,
+    )));
+    if let Err(e) = buffer.lock().unwrap().load() {
+        error!("Load buffer error: {}, server: {}", e, name);
+    }
+
+    // The Concentratord events and command client are shared between restarts of
+    // the forwarder, so that only the UDP side is re-connected.
+    let events = Arc::new(Mutex::new(events));
+    let command_client = Arc::new(Mutex::new(
+        commands::Client::new(
+            &concentratord.command_url,
+            time::Duration::from_millis(concentratord.command_timeout_ms),
+            concentratord.command_retries,
+        )
+        .expect("get commands client error"),
+    ));
+
+    // In case configured, the gateway id override is sent to the server, but the
+    // Concentratord gateway id is still used for sending downlinks.
+    let upstream_gateway_id = conf
+        .get_gateway_id()
+        .expect("gateway_id validated by configuration");
+    if let Some(v) = &upstream_gateway_id {
+        info!(
+            "Using gateway ID override, gateway_id: {}, server: {}",
+            hex::encode(v),
+            name
+        );
+    }
+
+    let mut backoff = Backoff::new(RECONNECT_BACKOFF_MIN, RECONNECT_BACKOFF_MAX);
+
+    // The (up, down) server addresses that stopped responding, these are only
+    // retried after the other resolved addresses.
+    let mut failed_addrs: (Option<SocketAddr>, Option<SocketAddr>) = (None, None);
+
     // loop so that we can restart the forwarder
     loop {
-        info!("Starting forwarder, server: {}", conf.server);
+        info!("Starting forwarder, server: {}", name);
 
-        // setup udp socket
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("udp socket bind error");
-        socket
-            .connect(&conf.server)
-            .expect("connect udp socket error");
-        socket
-            .set_read_timeout(Some(time::Duration::from_millis(100)))
-            .unwrap();
+        // setup udp sockets
+        let (socket_up, socket_down) = match connect_sockets(conf, failed_addrs) {
+            Ok(v) => v,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                error!(
+                    "Setup UDP socket error: {}, server: {}, retry in: {:?}",
+                    e, name, delay
+                );
+                metrics::incr_server_connect_errors(name);
+                if let Some(member) = &failover_member {
+                    member.set_failed();
+                }
+
+                thread::sleep(delay);
+                continue;
+            }
+        };
+        backoff.reset();
 
         // setup state
         let state = State {
-            socket,
-            server: conf.server.clone(),
+            socket_up,
+            socket_down,
+            server: name.to_string(),
+            protocol_version: conf.protocol_version,
             keepalive_interval: match conf.keepalive_interval_secs {
                 0 => time::Duration::from_secs(5),
                 _ => time::Duration::from_secs(conf.keepalive_interval_secs),
@@ -127,20 +199,41 @@ pub fn start(conf: &Server, event_url: String, command_url: String, gateway_id:
             forward_crc_ok: conf.forward_crc_ok,
             forward_crc_invalid: conf.forward_crc_invalid,
             forward_crc_missing: conf.forward_crc_missing,
+            filters: Filters::from_config(conf),
             keepalive_max_failures: conf.keepalive_max_failures,
-            gateway_id: gateway_id.clone(),
-            push_data_token: Mutex::new(0),
-            push_data_sent: Mutex::new(0),
-            push_data_acked: Mutex::new(0),
+            downlink_mode: conf.downlink_mode,
+            failover_member: failover_member.clone(),
+            virtual_gateway: virtual_gateway.clone(),
+            stat_counters: match &virtual_gateway {
+                Some(vg) => conf
+                    .virtual_gateways
+                    .first()
+                    .is_none_or(|v| v.gateway_id == vg.gateway_id),
+                None => true,
+            },
+            aggregation_interval: time::Duration::from_millis(conf.aggregation_interval_ms),
+            max_push_data_size: conf.max_push_data_size,
+            retransmit_batch_size: conf.buffer_retransmit_batch_size,
+            gateway_id: gateway_id.read().unwrap().clone(),
+            upstream_gateway_id: match &upstream_gateway_id {
+                Some(v) => v.clone(),
+                None => gateway_id.read().unwrap().clone(),
+            },
+            current_gateway_id: gateway_id.clone(),
+            push_data_tokens: Mutex::new(PushDataTokens::new(
+                PUSH_DATA_TOKEN_WINDOW_MIN.max(conf.buffer_size),
+            )),
             pull_data_token: Mutex::new(0),
             pull_data_token_acked: Mutex::new(0),
             rxfw: Mutex::new(0),
-            event_sock: Mutex::new(
-                events::get_socket(&event_url).expect("get events client error"),
-            ),
-            command_sock: Mutex::new(
-                commands::get_socket(&command_url).expect("get commands client error"),
-            ),
+            pending_push_data: Mutex::new(PendingPushData::default()),
+            pull_resp_dedup: Mutex::new(PullRespDedup::new(time::Duration::from_secs(
+                conf.pull_resp_dedup_ttl_secs,
+            ))),
+            buffer: buffer.clone(),
+            retransmit_pending: Mutex::new(true),
+            events: events.clone(),
+            command_client: command_client.clone(),
         };
         let state = Arc::new(state);
 
@@ -158,10 +251,22 @@ pub fn start(conf: &Server, event_url: String, command_url: String, gateway_id:
             let stop_receive = signal_pool.new_receiver();
 
             move || {
-                udp_receive_loop(state, stop_receive);
+                udp_receive_loop(state, false, stop_receive);
             }
         }));
 
+        // UDP receive loop for the downlink socket, in case separate ports are used.
+        if state.socket_down.is_some() {
+            threads.push(thread::spawn({
+                let state = state.clone();
+                let stop_receive = signal_pool.new_receiver();
+
+                move || {
+                    udp_receive_loop(state, true, stop_receive);
+                }
+            }));
+        }
+
         // event thread.
         threads.push(thread::spawn({
             let state = state.clone();
@@ -181,14 +286,49 @@ pub fn start(conf: &Server, event_url: String, command_url: String, gateway_id:
             }
         }));
 
+        metrics::set_server_connected(name, true);
+
         for t in threads {
             t.join().unwrap();
         }
 
-        warn!("Forwarder stopped, server: {}", conf.server);
+        metrics::set_server_connected(name, false);
+        warn!("Forwarder stopped, server: {}", name);
+
+        // In case the last PULL_DATA was not acknowledged, the next resolved address
+        // is tried on restart, e.g. in case of a broken IPv6 path.
+        failed_addrs = match state.get_pull_data_token() != state.get_pull_data_token_acked() {
+            true => (
+                state.socket_up.peer_addr().ok(),
+                state.socket_down.as_ref().and_then(|v| v.peer_addr().ok()),
+            ),
+            false => (None, None),
+        };
     }
 }
 
+// Setup the UDP socket(s) for the server. The second socket is only returned in
+// case a separate downlink port has been configured.
+fn connect_sockets(
+    conf: &Server,
+    failed_addrs: (Option<SocketAddr>, Option<SocketAddr>),
+) -> Result<(UdpSocket, Option<UdpSocket>)> {
+    let socket_up = udp::connect(conf, &conf.server, conf.bind_port, failed_addrs.0)?;
+    socket_up.set_read_timeout(Some(time::Duration::from_millis(100)))?;
+
+    let socket_down = match conf.server_port_down {
+        0 => None,
+        port => {
+            let server = udp::replace_port(&conf.server, port)?;
+            let socket = udp::connect(conf, &server, conf.bind_port_down, failed_addrs.1)?;
+            socket.set_read_timeout(Some(time::Duration::from_millis(100)))?;
+            Some(socket)
+        }
+    };
+
+    Ok((socket_up, socket_down))
+}
+
 fn pull_data_loop(state: Arc<State>, signal_pool: signals::SignalPool) {
     let mut missed_acks: u32 = 0;
 
@@ -200,15 +340,42 @@ fn pull_data_loop(state: Arc<State>, signal_pool: signals::SignalPool) {
                 state.get_pull_data_token()
             );
             missed_acks += 1;
+
+            // A flapping server must not complete its failover hold-down period.
+            if let Some(member) = &state.failover_member {
+                member.set_unhealthy();
+            }
+
+            // Re-send the unacknowledged PUSH_DATA once the server acknowledges the
+            // PULL_DATA again.
+            *state.retransmit_pending.lock().unwrap() = true;
         } else {
             missed_acks = 0;
         }
 
+        if let Err(e) = state.buffer.lock().unwrap().save() {
+            error!("Save buffer error: {}, server: {}", e, state.server);
+        }
+
         if state.keepalive_max_failures != 0 && missed_acks > state.keepalive_max_failures {
             warn!(
                 "Max missed keepalive frames missed, server: {}",
                 state.server
             );
+            if let Some(member) = &state.failover_member {
+                member.set_failed();
+            }
+            signal_pool.send_signal(signals::Signal::Stop);
+
+            debug!("Terminating PULL_DATA loop, server: {}", state.server);
+            return;
+        }
+
+        if *state.current_gateway_id.read().unwrap() != state.gateway_id {
+            warn!(
+                "Gateway ID has changed, restarting forwarder, server: {}",
+                state.server
+            );
             signal_pool.send_signal(signals::Signal::Stop);
 
             debug!("Terminating PULL_DATA loop, server: {}", state.server);
@@ -216,16 +383,17 @@ fn pull_data_loop(state: Arc<State>, signal_pool: signals::SignalPool) {
         }
 
         let mut id: [u8; 8] = [0; 8];
-        id.copy_from_slice(&state.gateway_id);
+        id.copy_from_slice(&state.upstream_gateway_id);
 
         let pull_data = structs::PullData {
+            protocol_version: state.protocol_version,
             gateway_id: id,
             random_token: state.set_pull_data_token(),
         };
         let bytes = pull_data.to_bytes();
 
         info!("Sending PULL_DATA to server, server: {}", state.server);
-        if let Err(e) = state.socket.send(&bytes) {
+        if let Err(e) = state.get_socket_down().send(&bytes) {
             error!("UDP send error: {}, server: {}", e, state.server);
         };
 
@@ -236,8 +404,12 @@ fn pull_data_loop(state: Arc<State>, signal_pool: signals::SignalPool) {
     }
 }
 
-fn udp_receive_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
+fn udp_receive_loop(state: Arc<State>, down: bool, stop_receive: Receiver<signals::Signal>) {
     let mut buffer: [u8; 65535] = [0; 65535];
+    let socket = match down {
+        true => state.get_socket_down(),
+        false => &state.socket_up,
+    };
 
     loop {
         if stop_receive
@@ -248,7 +420,7 @@ fn udp_receive_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>)
             return;
         };
 
-        let size = match state.socket.recv(&mut buffer) {
+        let size = match socket.recv(&mut buffer) {
             Ok(v) => v,
             Err(_) => {
                 // Most likely, a timeout occured.
@@ -304,30 +476,32 @@ fn udp_receive_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>)
 }
 
 fn events_loop(state: Arc<State>, stop_receive: Receiver<signals::Signal>) {
-    let event_sock = state.event_sock.lock().unwrap();
-    let reader = events::Reader::new(&event_sock, time::Duration::from_millis(100));
+    let events = state.events.lock().unwrap();
 
-    for cmd in reader {
+    loop {
         if stop_receive
             .recv_timeout(time::Duration::from_millis(0))
             .is_ok()
         {
+            flush_push_data(&state, true);
+
             debug!("Terminating events loop, server: {}", state.server);
             return;
         }
 
-        match cmd {
+        flush_push_data(&state, false);
+
+        match events.recv_timeout(time::Duration::from_millis(100)) {
             Ok(v) => match v.event {
                 Some(gw::event::Event::UplinkFrame(pl)) => events_up(&state, pl),
                 Some(gw::event::Event::GatewayStats(pl)) => events_stats(&state, pl),
                 _ => continue,
             },
-            Err(e) => match e {
-                events::Error::Timeout => continue,
-                _ => {
-                    warn!("Read event error, error: {}", e);
-                }
-            },
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("Events channel disconnected, server: {}", state.server);
+                return;
+            }
         }
     }
 }
@@ -342,37 +516,35 @@ fn events_stats(state: &Arc<State>, stats: chirpstack_api::gw::GatewayStats) {
     };
     stat.rxfw = state.get_and_reset_rxfw();
 
-    let pd_sent = state.get_and_reset_push_data_sent();
-    let pd_acked = state.get_and_reset_push_data_acked();
-    if pd_sent != 0 {
-        stat.ackr = pd_acked as f32 / pd_sent as f32 * 100.0
+    // The concentrator counters are shared by the virtual gateways, these are only
+    // reported by the first virtual gateway so that the server does not count these
+    // multiple times.
+    if !state.stat_counters {
+        stat.rxnb = 0;
+        stat.rxok = 0;
+        stat.dwnb = 0;
+        stat.txnb = 0;
     }
 
-    let mut id: [u8; 8] = [0; 8];
-    id.copy_from_slice(&state.gateway_id);
-
-    let push_data = structs::PushData {
-        random_token: state.set_push_data_token(),
-        gateway_id: id,
-        payload: structs::PushDataPayload {
-            stat: Some(stat),
-            rxpk: vec![],
-        },
-    };
-    let bytes = push_data.to_bytes();
-
-    info!(
-        "Sending PUSH_DATA with stats to server, server: {}",
-        state.server
-    );
-    if let Err(e) = state.socket.send(&bytes) {
-        error!("UDP send error: {}, server: {}", e, state.server);
-    };
+    if let Some(ackr) = state.push_data_tokens.lock().unwrap().get_and_reset_ackr() {
+        stat.ackr = ackr;
+    }
 
-    state.incr_push_data_sent();
+    if state.aggregation_interval.is_zero() {
+        send_push_data(
+            state,
+            structs::PushDataPayload {
+                stat: Some(stat),
+                rxpk: vec![],
+            },
+        );
+        return;
+    }
 
-    metrics::incr_udp_sent_count(&state.server, "PUSH_DATA_STATS");
-    metrics::incr_udp_sent_bytes(&state.server, "PUSH_DATA_STATS", bytes.len());
+    let mut pending = state.pending_push_data.lock().unwrap();
+    for payload in pending.set_stat(stat, state.max_push_data_size) {
+        send_push_data(state, payload);
+    }
 }
 
 fn events_up(state: &Arc<State>, up: chirpstack_api::gw::UplinkFrame) {
@@ -385,6 +557,29 @@ fn events_up(state: &Arc<State>, up: chirpstack_api::gw::UplinkFrame) {
         }
     }
 
+    // In case of a virtual gateway, only forward the uplinks received by the
+    // mapped board, antenna and optionally RF chain.
+    if let Some(vg) = &state.virtual_gateway {
+        if !filters::matches_virtual_gateway(vg, &up) {
+            return;
+        }
+    }
+
+    if !state.filters.matches(&up) {
+        debug!("Uplink does not match filters, server: {}", state.server);
+        return;
+    }
+
+    if let Some(member) = &state.failover_member {
+        if !member.is_active() {
+            debug!(
+                "Server is not active within failover group, server: {}",
+                state.server
+            );
+            return;
+        }
+    }
+
     let rxpk = match structs::RxPk::from_proto(&up) {
         Ok(v) => v,
         Err(err) => {
@@ -393,52 +588,122 @@ fn events_up(state: &Arc<State>, up: chirpstack_api::gw::UplinkFrame) {
         }
     };
 
+    if state.aggregation_interval.is_zero() {
+        send_push_data(
+            state,
+            structs::PushDataPayload {
+                stat: None,
+                rxpk: vec![rxpk],
+            },
+        );
+        return;
+    }
+
+    let mut pending = state.pending_push_data.lock().unwrap();
+    for payload in pending.push_rxpk(rxpk, state.max_push_data_size) {
+        send_push_data(state, payload);
+    }
+}
+
+fn flush_push_data(state: &Arc<State>, force: bool) {
+    let mut pending = state.pending_push_data.lock().unwrap();
+    if pending.is_empty() || !(force || pending.is_due(state.aggregation_interval)) {
+        return;
+    }
+
+    send_push_data(state, pending.take());
+}
+
+fn send_push_data(state: &Arc<State>, payload: structs::PushDataPayload) {
     let mut id: [u8; 8] = [0; 8];
-    id.copy_from_slice(&state.gateway_id);
+    id.copy_from_slice(&state.upstream_gateway_id);
+
+    let rxpk_count = payload.rxpk.len() as u32;
+    let metrics_key = if rxpk_count == 0 {
+        "PUSH_DATA_STATS"
+    } else {
+        "PUSH_DATA_RXPK"
+    };
 
     let push_data = structs::PushData {
+        protocol_version: state.protocol_version,
         random_token: state.set_push_data_token(),
         gateway_id: id,
-        payload: structs::PushDataPayload {
-            stat: None,
-            rxpk: vec![rxpk],
-        },
+        payload,
     };
     let bytes = push_data.to_bytes();
 
     info!(
-        "Sending PUSH_DATA with rxpk to server, server: {}",
+        "Sending PUSH_DATA to server, rxpk: {}, stat: {}, server: {}",
+        rxpk_count,
+        push_data.payload.stat.is_some(),
         state.server
     );
-    if let Err(e) = state.socket.send(&bytes) {
+    if let Err(e) = state.socket_up.send(&bytes) {
         error!("UDP send error: {}, server: {}", e, state.server);
     };
 
-    state.incr_rxfw();
-    state.incr_push_data_sent();
+    // Only the rxpk are buffered, as re-sending the stat would count the
+    // concentrator counters twice.
+    if rxpk_count != 0 {
+        let data = serde_json::to_vec(&serde_json::json!({ "rxpk": push_data.payload.rxpk }))
+            .unwrap_or_default();
+        if state
+            .buffer
+            .lock()
+            .unwrap()
+            .push(push_data.random_token, data)
+        {
+            warn!(
+                "Buffer is full, dropped oldest PUSH_DATA, server: {}",
+                state.server
+            );
+        }
+    }
 
-    metrics::incr_udp_sent_count(&state.server, "PUSH_DATA_RXPK");
-    metrics::incr_udp_sent_bytes(&state.server, "PUSH_DATA_RXPK", bytes.len());
+    state.incr_rxfw(rxpk_count);
+
+    metrics::incr_udp_sent_count(&state.server, metrics_key);
+    metrics::incr_udp_sent_bytes(&state.server, metrics_key, bytes.len());
 }
 
 fn handle_push_ack(state: &Arc<State>, data: &[u8]) -> Result<()> {
-    let push_ack = structs::PushAck::from_bytes(data)?;
-    let expected_token = state.get_push_data_token();
+    let push_ack = structs::PushAck::from_bytes(data, state.protocol_version)?;
 
-    if push_ack.random_token == expected_token {
+    if state.buffer.lock().unwrap().ack(push_ack.random_token) {
         debug!(
-            "PUSH_DATA acknowledged, token: {}, server: {}",
-            expected_token, state.server
+            "Removed acknowledged PUSH_DATA from buffer, token: {}, server: {}",
+            push_ack.random_token, state.server
         );
+    }
+
+    let rtt = state
+        .push_data_tokens
+        .lock()
+        .unwrap()
+        .ack(push_ack.random_token);
+    match rtt {
+        Some(rtt) => {
+            debug!(
+                "PUSH_DATA acknowledged, token: {}, rtt: {:?}, server: {}",
+                push_ack.random_token, rtt, state.server
+            );
 
-        state.incr_push_data_acked();
+            metrics::observe_udp_push_ack_rtt(&state.server, rtt);
+        }
+        None => {
+            debug!(
+                "Ignoring PUSH_ACK with unknown token, token: {}, server: {}",
+                push_ack.random_token, state.server
+            );
+        }
     }
 
     Ok(())
 }
 
 fn handle_pull_ack(state: &Arc<State>, data: &[u8]) -> Result<()> {
-    let push_ack = structs::PullAck::from_bytes(data)?;
+    let push_ack = structs::PullAck::from_bytes(data, state.protocol_version)?;
     let expected_token = state.get_pull_data_token();
     state.set_pull_data_token_acked(push_ack.random_token);
 
@@ -447,95 +712,215 @@ fn handle_pull_ack(state: &Arc<State>, data: &[u8]) -> Result<()> {
             "PULL_DATA acknowledged, token: {}, server: {}",
             expected_token, state.server
         );
+
+        if let Some(member) = &state.failover_member {
+            member.set_healthy();
+        }
+
+        retransmit_push_data(state);
     }
 
     Ok(())
 }
 
+// Re-send a batch of the unacknowledged PUSH_DATA, in case the server did not
+// acknowledge the PULL_DATA before (e.g. during a backhaul outage). Otherwise the
+// PUSH_ACK was lost while the server was reachable and the PUSH_DATA is dropped.
+fn retransmit_push_data(state: &Arc<State>) {
+    let items = {
+        let mut buffer = state.buffer.lock().unwrap();
+        if !buffer.is_enabled() {
+            return;
+        }
+
+        let expired = buffer.remove_expired();
+        if expired != 0 {
+            warn!(
+                "Dropped expired PUSH_DATA from buffer, count: {}, server: {}",
+                expired, state.server
+            );
+        }
+
+        let mut pending = state.retransmit_pending.lock().unwrap();
+        if !*pending {
+            let unacked = buffer.remove_unacked(state.keepalive_interval);
+            if unacked != 0 {
+                warn!(
+                    "Dropped unacknowledged PUSH_DATA from buffer, count: {}, server: {}",
+                    unacked, state.server
+                );
+            }
+            return;
+        }
+
+        let items = buffer.get_retransmit(state.keepalive_interval, state.retransmit_batch_size);
+        if items.len() < state.retransmit_batch_size {
+            *pending = false;
+        }
+        items
+    };
+
+    let mut id: [u8; 8] = [0; 8];
+    id.copy_from_slice(&state.upstream_gateway_id);
+
+    for (token, data) in items {
+        let mut bytes = structs::PushData::header_bytes(state.protocol_version, token, id);
+        bytes.extend_from_slice(&data);
+
+        info!(
+            "Re-sending unacknowledged PUSH_DATA to server, token: {}, server: {}",
+            token, state.server
+        );
+        if let Err(e) = state.socket_up.send(&bytes) {
+            error!("UDP send error: {}, server: {}", e, state.server);
+        }
+
+        state.push_data_tokens.lock().unwrap().add(token);
+
+        metrics::incr_udp_sent_count(&state.server, "PUSH_DATA_RETRANSMIT");
+        metrics::incr_udp_sent_bytes(&state.server, "PUSH_DATA_RETRANSMIT", bytes.len());
+    }
+}
+
 fn handle_pull_resp(state: &Arc<State>, data: &[u8]) -> Result<()> {
-    let pull_resp = structs::PullResp::from_bytes(data)?;
-    let sock = state.command_sock.lock().unwrap();
-
-    let pl = match pull_resp
-        .payload
-        .txpk
-        .to_proto(pull_resp.random_token as u32, state.gateway_id.clone())
-    {
+    let pull_resp = structs::PullResp::from_bytes(data, state.protocol_version)?;
+
+    let duplicate = state
+        .pull_resp_dedup
+        .lock()
+        .unwrap()
+        .get_duplicate(pull_resp.random_token, &data[4..]);
+
+    if let Some(error) = duplicate {
+        warn!(
+            "Ignoring duplicated PULL_RESP, token: {}, server: {}",
+            pull_resp.random_token, state.server
+        );
+        metrics::incr_udp_received_count(&state.server, "PULL_RESP_DUPLICATE");
+
+        send_tx_ack(state, pull_resp.random_token, error);
+        return Ok(());
+    }
+
+    match state.downlink_mode {
+        DownlinkMode::Allow => {}
+        DownlinkMode::Deny => {
+            warn!(
+                "Downlinks are denied for this server, ignoring PULL_RESP, token: {}, server: {}",
+                pull_resp.random_token, state.server
+            );
+        }
+        DownlinkMode::DryRun => {
+            info!(
+                "Dry-run mode, not sending downlink to Concentratord, token: {}, server: {}, payload: {}",
+                pull_resp.random_token,
+                state.server,
+                String::from_utf8_lossy(&data[4..])
+            );
+        }
+    }
+
+    let error = get_downlink_tx_ack_error(state.downlink_mode, || {
+        send_downlink_frame(state, &pull_resp)
+    })?;
+
+    state
+        .pull_resp_dedup
+        .lock()
+        .unwrap()
+        .add(pull_resp.random_token, &data[4..], error.clone());
+    send_tx_ack(state, pull_resp.random_token, error);
+
+    Ok(())
+}
+
+// Returns the TX_ACK error for a PULL_RESP given the downlink mode. The send
+// function, which sends the downlink to the Concentratord and returns its TX_ACK
+// error, is only called when downlinks are allowed.
+fn get_downlink_tx_ack_error<F>(mode: DownlinkMode, send: F) -> Result<String>
+where
+    F: FnOnce() -> Result<String>,
+{
+    match mode {
+        DownlinkMode::Allow => send(),
+        DownlinkMode::Deny => Ok("IGNORED".to_string()),
+        DownlinkMode::DryRun => Ok("".to_string()),
+    }
+}
+
+fn send_downlink_frame(state: &Arc<State>, pull_resp: &structs::PullResp) -> Result<String> {
+    let pl = match pull_resp.payload.txpk.to_proto(
+        pull_resp.random_token as u32,
+        state.gateway_id.clone(),
+        state.virtual_gateway.as_ref().map(|v| v.board).unwrap_or(0),
+        state
+            .virtual_gateway
+            .as_ref()
+            .map(|v| v.antenna)
+            .unwrap_or(0),
+    ) {
         Ok(v) => v,
         Err(err) => {
             return Err(anyhow!("TxPk to proto error: {}", err));
         }
     };
 
-    let pl = gw::Command {
-        command: Some(gw::command::Command::SendDownlinkFrame(pl)),
+    let tx_ack = match state.command_client.lock().unwrap().send_downlink_frame(pl) {
+        Ok(v) => v,
+        Err(err) => {
+            return Err(anyhow!("send downlink command error: {}", err));
+        }
     };
-    let b = pl.encode_to_vec();
-
-    // send 'down' command with payload
-    sock.send(b, 0).unwrap();
 
-    // set poller so that we can timeout after 100ms
-    let mut items = [sock.as_poll_item(zmq::POLLIN)];
-    zmq::poll(&mut items, 100).unwrap();
-    if !items[0].is_readable() {
-        return Err(anyhow!("could not read down response"));
+    if tx_ack.items.len() != 1 {
+        return Err(anyhow!(""));
     }
 
-    // read tx ack response.
-    let resp_b: &[u8] = &sock.recv_bytes(0).unwrap();
-    let tx_ack = match chirpstack_api::gw::DownlinkTxAck::decode(resp_b) {
-        Ok(v) => v,
-        Err(err) => {
-            return Err(anyhow!("decode DownlinkTxAck error: {}", err));
-        }
+    let error = match tx_ack.items[0].status() {
+        chirpstack_api::gw::TxAckStatus::Ok => "".to_string(),
+        chirpstack_api::gw::TxAckStatus::Ignored => "IGNORED".to_string(),
+        chirpstack_api::gw::TxAckStatus::TooLate => "TOO_LATE".to_string(),
+        chirpstack_api::gw::TxAckStatus::TooEarly => "TOO_EARLY".to_string(),
+        chirpstack_api::gw::TxAckStatus::CollisionPacket => "COLLISION_PACKET".to_string(),
+        chirpstack_api::gw::TxAckStatus::CollisionBeacon => "COLLISION_BEACON".to_string(),
+        chirpstack_api::gw::TxAckStatus::TxFreq => "TX_FREQ".to_string(),
+        chirpstack_api::gw::TxAckStatus::TxPower => "TX_POWER".to_string(),
+        chirpstack_api::gw::TxAckStatus::GpsUnlocked => "GPS_UNLOCKED".to_string(),
+        chirpstack_api::gw::TxAckStatus::QueueFull => "QUEUE_FULL".to_string(),
+        chirpstack_api::gw::TxAckStatus::InternalError => "INTERNAL_ERROR".to_string(),
+        chirpstack_api::gw::TxAckStatus::DutyCycleOverflow => "DUTY_CYCLE_OVERFLOW".to_string(),
     };
 
+    Ok(error)
+}
+
+fn send_tx_ack(state: &Arc<State>, random_token: u16, error: String) {
+    // TX_ACK was introduced in protocol version 2.
+    if state.protocol_version == structs::PROTOCOL_VERSION_1 {
+        debug!(
+            "Skipping TX_ACK for protocol version 1, server: {}",
+            state.server
+        );
+        return;
+    }
+
     // udp tx ack
     let tx_ack_udp = structs::TxAck {
-        random_token: pull_resp.random_token,
+        protocol_version: state.protocol_version,
+        random_token,
         gateway_id: {
             let mut id: [u8; 8] = [0; 8];
-            id.copy_from_slice(&state.gateway_id);
+            id.copy_from_slice(&state.upstream_gateway_id);
             id
         },
         payload: structs::TxAckPayload {
-            txpk_ack: structs::TxAckPayloadError {
-                error: {
-                    if tx_ack.items.len() != 1 {
-                        return Err(anyhow!(""));
-                    }
-
-                    match tx_ack.items[0].status() {
-                        chirpstack_api::gw::TxAckStatus::Ok => "".to_string(),
-                        chirpstack_api::gw::TxAckStatus::Ignored => "IGNORED".to_string(),
-                        chirpstack_api::gw::TxAckStatus::TooLate => "TOO_LATE".to_string(),
-                        chirpstack_api::gw::TxAckStatus::TooEarly => "TOO_EARLY".to_string(),
-                        chirpstack_api::gw::TxAckStatus::CollisionPacket => {
-                            "COLLISION_PACKET".to_string()
-                        }
-                        chirpstack_api::gw::TxAckStatus::CollisionBeacon => {
-                            "COLLISION_BEACON".to_string()
-                        }
-                        chirpstack_api::gw::TxAckStatus::TxFreq => "TX_FREQ".to_string(),
-                        chirpstack_api::gw::TxAckStatus::TxPower => "TX_POWER".to_string(),
-                        chirpstack_api::gw::TxAckStatus::GpsUnlocked => "GPS_UNLOCKED".to_string(),
-                        chirpstack_api::gw::TxAckStatus::QueueFull => "QUEUE_FULL".to_string(),
-                        chirpstack_api::gw::TxAckStatus::InternalError => {
-                            "INTERNAL_ERROR".to_string()
-                        }
-                        chirpstack_api::gw::TxAckStatus::DutyCycleOverflow => {
-                            "DUTY_CYCLE_OVERFLOW".to_string()
-                        }
-                    }
-                },
-            },
+            txpk_ack: structs::TxAckPayloadError { error },
         },
     };
     let bytes = tx_ack_udp.to_bytes();
 
     debug!("Sending TX_ACK to server, server: {}", state.server);
-    if let Err(e) = state.socket.send(&bytes) {
+    if let Err(e) = state.get_socket_down().send(&bytes) {
         error!("UDP send error: {}, server: {}", e, state.server);
     };
 
@@ -546,6 +931,30 @@ fn handle_pull_resp(state: &Arc<State>, data: &[u8]) -> Result<()> {
 
     metrics::incr_udp_sent_count(&state.server, &metrics_key);
     metrics::incr_udp_sent_bytes(&state.server, &metrics_key, bytes.len());
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_downlink_tx_ack_error() {
+        assert_eq!(
+            "TOO_LATE",
+            get_downlink_tx_ack_error(DownlinkMode::Allow, || Ok("TOO_LATE".to_string())).unwrap()
+        );
+        assert!(
+            get_downlink_tx_ack_error(DownlinkMode::Allow, || Err(anyhow!("timeout"))).is_err()
+        );
+
+        // Denied and dry-run downlinks never reach the Concentratord.
+        assert_eq!(
+            "IGNORED",
+            get_downlink_tx_ack_error(DownlinkMode::Deny, || panic!("downlink sent")).unwrap()
+        );
+        assert_eq!(
+            "",
+            get_downlink_tx_ack_error(DownlinkMode::DryRun, || panic!("downlink sent")).unwrap()
+        );
+    }
 }
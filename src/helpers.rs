@@ -1,31 +1,176 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
-use chirpstack_api::{gw, prost::Message};
 
+use super::backoff::Backoff;
 use super::commands;
 
-pub fn get_gateway_id(command_url: &str) -> Result<Vec<u8>> {
-    debug!("Reading gateway id, server: {}", command_url);
+pub fn get_gateway_id(client: &mut commands::Client) -> Result<Vec<u8>> {
+    debug!("Reading gateway id");
+
+    let resp = client
+        .get_gateway_id()
+        .map_err(|e| anyhow!("could not read gateway_id: {}", e))?;
+
+    Ok(hex::decode(resp.gateway_id)?)
+}
+
+// Retry reading the gateway id until Concentratord responds, e.g. in case the
+// forwarder was started before Concentratord. A zero timeout waits indefinitely.
+pub fn wait_for_gateway_id(client: &mut commands::Client, timeout: Duration) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(5));
 
-    let sock = commands::get_socket(command_url).expect("get client error");
+    loop {
+        let err = match get_gateway_id(client) {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
 
-    // Send command.
-    let cmd = gw::Command {
-        command: Some(gw::command::Command::GetGatewayId(
-            gw::GetGatewayIdRequest {},
-        )),
-    };
-    sock.send(cmd.encode_to_vec(), 0).unwrap();
+        let elapsed = start.elapsed();
+        if !timeout.is_zero() && elapsed >= timeout {
+            return Err(err);
+        }
 
-    // set poller so that we can timout after 100ms
-    let mut items = [sock.as_poll_item(zmq::POLLIN)];
-    zmq::poll(&mut items, 100).unwrap();
-    if !items[0].is_readable() {
-        return Err(anyhow!("could not read gateway_id"));
+        let mut delay = backoff.next_delay();
+        if !timeout.is_zero() {
+            delay = delay.min(timeout - elapsed);
+        }
+
+        warn!(
+            "Waiting for Concentratord, error: {}, elapsed: {:?}, retry in: {:?}",
+            err, elapsed, delay
+        );
+        thread::sleep(delay);
     }
+}
 
-    // read 'gateway_id' response
-    let b = sock.recv_bytes(0).unwrap();
-    let resp = gw::GetGatewayIdResponse::decode(b.as_slice()).unwrap();
+// Re-read the gateway id in the given interval, or when the Concentratord event
+// stream resumes after a gap, and update the shared gateway id when it changed.
+// The forwarders restart on a changed gateway id.
+pub fn gateway_id_loop(
+    mut client: commands::Client,
+    gateway_id: Arc<RwLock<Vec<u8>>>,
+    interval: Duration,
+    resume: Receiver<()>,
+) {
+    loop {
+        match resume.recv_timeout(interval) {
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(interval);
+            }
+        }
 
-    Ok(hex::decode(resp.gateway_id)?)
+        let id = match get_gateway_id(&mut client) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Re-reading gateway ID error: {}", e);
+                continue;
+            }
+        };
+
+        let mut current = gateway_id.write().unwrap();
+        if *current != id {
+            warn!(
+                "Gateway ID has changed, old_gateway_id: {}, gateway_id: {}",
+                hex::encode(&*current),
+                hex::encode(&id)
+            );
+            *current = id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use chirpstack_api::{gw, prost::Message};
+
+    use super::*;
+    use crate::socket::ZMQ_CONTEXT;
+
+    #[test]
+    fn test_wait_for_gateway_id() {
+        let rep = ZMQ_CONTEXT.lock().unwrap().socket(zmq::REP).unwrap();
+        rep.bind("inproc://helpers_test").unwrap();
+
+        let mut client =
+            commands::Client::new("inproc://helpers_test", Duration::from_millis(50), 0).unwrap();
+
+        // Only answer the third request, the replies to the first two requests are
+        // sent after the client timed out.
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                rep.recv_bytes(0).unwrap();
+                thread::sleep(Duration::from_millis(100));
+                rep.send(vec![], 0).unwrap();
+            }
+
+            rep.recv_bytes(0).unwrap();
+            let resp = gw::GetGatewayIdResponse {
+                gateway_id: "0102030405060708".into(),
+            };
+            rep.send(resp.encode_to_vec(), 0).unwrap();
+        });
+
+        assert_eq!(
+            vec![1, 2, 3, 4, 5, 6, 7, 8],
+            wait_for_gateway_id(&mut client, Duration::from_secs(10)).unwrap()
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_gateway_id_timeout() {
+        let rep = ZMQ_CONTEXT.lock().unwrap().socket(zmq::REP).unwrap();
+        rep.bind("inproc://helpers_test_timeout").unwrap();
+
+        let mut client = commands::Client::new(
+            "inproc://helpers_test_timeout",
+            Duration::from_millis(50),
+            0,
+        )
+        .unwrap();
+
+        // Concentratord never answers, the deadline is reached.
+        let start = Instant::now();
+        assert!(wait_for_gateway_id(&mut client, Duration::from_millis(500)).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+    #[test]
+    fn test_gateway_id_loop() {
+        let rep = ZMQ_CONTEXT.lock().unwrap().socket(zmq::REP).unwrap();
+        rep.bind("inproc://helpers_test_loop").unwrap();
+
+        let client =
+            commands::Client::new("inproc://helpers_test_loop", Duration::from_millis(500), 0)
+                .unwrap();
+        let gateway_id = Arc::new(RwLock::new(vec![1, 2, 3, 4, 5, 6, 7, 8]));
+        let (resume_tx, resume_rx) = channel();
+
+        thread::spawn({
+            let gateway_id = gateway_id.clone();
+            move || gateway_id_loop(client, gateway_id, Duration::from_secs(60), resume_rx)
+        });
+
+        // The event stream resumed, Concentratord returns a new gateway id.
+        resume_tx.send(()).unwrap();
+        rep.recv_bytes(0).unwrap();
+        let resp = gw::GetGatewayIdResponse {
+            gateway_id: "0807060504030201".into(),
+        };
+        rep.send(resp.encode_to_vec(), 0).unwrap();
+
+        let start = Instant::now();
+        while *gateway_id.read().unwrap() != vec![8, 7, 6, 5, 4, 3, 2, 1] {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
 }
@@ -3,14 +3,24 @@ extern crate log;
 #[macro_use]
 extern crate anyhow;
 
+use std::process;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Duration;
 
+use anyhow::Result;
 use clap::Parser;
 
+mod aggregation;
+mod backoff;
+mod buffer;
 mod commands;
 mod config;
+mod dedup;
 mod events;
+mod failover;
+mod filters;
 mod forwarder;
 mod helpers;
 mod logging;
@@ -18,6 +28,8 @@ mod metrics;
 mod signals;
 mod socket;
 mod structs;
+mod tokens;
+mod udp;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -45,34 +57,165 @@ fn main() {
         "https://github.com/chirpstack/chirpstack-udp-forwarder",
     );
 
-    // read gateway id.
-    let gateway_id = helpers::get_gateway_id(&config.concentratord.command_url)
-        .expect("get gateway_id from concentratord failed, is concentratord running?");
-
-    info!(
-        "Received gateway ID from Concentratord, gateway_id: {}",
-        hex::encode(&gateway_id)
-    );
-
     // setup threads
     let mut threads: Vec<thread::JoinHandle<()>> = vec![];
 
+    // metrics, started first so that these are available while waiting for
+    // Concentratord.
+    if !config.udp_forwarder.metrics_bind.is_empty() {
+        threads.push(thread::spawn({
+            let bind = config.udp_forwarder.metrics_bind.clone();
+            move || metrics::start(bind)
+        }));
+    }
+
+    // read gateway id of each Concentratord, waiting for the instances concurrently.
+    let results: Vec<Result<(commands::Client, Vec<u8>)>> = thread::scope(|s| {
+        let handles: Vec<_> = config
+            .concentratords
+            .iter()
+            .map(|concentratord| {
+                s.spawn(move || -> Result<(commands::Client, Vec<u8>)> {
+                    let mut command_client = commands::Client::new(
+                        &concentratord.command_url,
+                        Duration::from_millis(concentratord.command_timeout_ms),
+                        concentratord.command_retries,
+                    )?;
+                    let gateway_id = helpers::wait_for_gateway_id(
+                        &mut command_client,
+                        Duration::from_secs(concentratord.startup_timeout_secs),
+                    )?;
+                    Ok((command_client, gateway_id))
+                })
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut command_clients: Vec<commands::Client> = vec![];
+    let mut gateway_ids: Vec<Arc<RwLock<Vec<u8>>>> = vec![];
+    for (concentratord, result) in config.concentratords.iter().zip(results) {
+        let (command_client, gateway_id) = match result {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Get gateway_id from Concentratord error: {}, concentratord: {}, is Concentratord running?",
+                    e, concentratord.name
+                );
+                process::exit(1);
+            }
+        };
+
+        info!(
+            "Received gateway ID from Concentratord, concentratord: {}, gateway_id: {}",
+            concentratord.name,
+            hex::encode(&gateway_id)
+        );
+        command_clients.push(command_client);
+        gateway_ids.push(Arc::new(RwLock::new(gateway_id)));
+    }
+
+    // A forwarder is started for each server and Concentratord (and virtual
+    // gateway) combination, as each gateway ID requires its own UDP socket for
+    // routing the downlinks. Each forwarder is named (e.g. 'server@card1') so
+    // that it can be identified in the logs and metrics.
+    let mut forwarders: Vec<(
+        String,
+        config::Server,
+        usize,
+        Option<config::VirtualGateway>,
+    )> = vec![];
+    for server in &config.udp_forwarder.servers {
+        for (i, concentratord) in config.concentratords.iter().enumerate() {
+            if !server.forwards_concentratord(&concentratord.name) {
+                continue;
+            }
+
+            let mut server = server.clone();
+            let mut name = server.server.clone();
+            if config.concentratords.len() > 1 {
+                name = format!("{}@{}", name, concentratord.name);
+                if !server.buffer_path.is_empty() {
+                    server.buffer_path = format!("{}.{}", server.buffer_path, concentratord.name);
+                }
+                if !server.failover_group.is_empty() {
+                    server.failover_group =
+                        format!("{}.{}", server.failover_group, concentratord.name);
+                }
+            }
+
+            if server.virtual_gateways.is_empty() {
+                forwarders.push((name, server, i, None));
+                continue;
+            }
+
+            for vg in &server.virtual_gateways {
+                let mut server = server.clone();
+                let name = format!("{}@{}", name, vg.gateway_id);
+                server.gateway_id = vg.gateway_id.clone();
+                if !server.buffer_path.is_empty() {
+                    server.buffer_path = format!("{}.{}", server.buffer_path, vg.gateway_id);
+                }
+                if !server.failover_group.is_empty() {
+                    server.failover_group = format!("{}.{}", server.failover_group, vg.gateway_id);
+                }
+                forwarders.push((name, server, i, Some(vg.clone())));
+            }
+        }
+    }
+
     // servers
-    for server in config.udp_forwarder.servers {
+    let mut dispatchers: Vec<events::Dispatcher> = config
+        .concentratords
+        .iter()
+        .map(|_| events::Dispatcher::new())
+        .collect();
+    let failover_members = failover::get_members(forwarders.iter().map(|(_, v, _, _)| v));
+    for ((name, server, i, virtual_gateway), failover_member) in
+        forwarders.into_iter().zip(failover_members)
+    {
         threads.push(thread::spawn({
-            let gateway_id = gateway_id.clone();
-            let event_url = config.concentratord.event_url.clone();
-            let command_url = config.concentratord.command_url.clone();
+            let gateway_id = gateway_ids[i].clone();
+            let events = dispatchers[i].new_receiver(&name);
+            let concentratord = config.concentratords[i].clone();
 
-            move || forwarder::start(&server, event_url, command_url, gateway_id)
+            move || {
+                forwarder::start(
+                    &name,
+                    &server,
+                    events,
+                    &concentratord,
+                    gateway_id,
+                    failover_member,
+                    virtual_gateway,
+                )
+            }
         }));
     }
 
-    // metrics
-    if !config.udp_forwarder.metrics_bind.is_empty() {
+    for ((concentratord, mut dispatcher), (command_client, gateway_id)) in config
+        .concentratords
+        .into_iter()
+        .zip(dispatchers)
+        .zip(command_clients.into_iter().zip(gateway_ids))
+    {
+        // gateway id
+        if concentratord.gateway_id_check_interval_secs != 0 {
+            threads.push(thread::spawn({
+                let resume = dispatcher.new_resume_receiver(Duration::from_secs(
+                    concentratord.gateway_id_check_event_gap_secs,
+                ));
+                let interval = Duration::from_secs(concentratord.gateway_id_check_interval_secs);
+
+                move || helpers::gateway_id_loop(command_client, gateway_id, interval, resume)
+            }));
+        }
+
+        // events
         threads.push(thread::spawn({
-            let bind = config.udp_forwarder.metrics_bind;
-            move || metrics::start(bind)
+            let event_url = concentratord.event_url.clone();
+            move || dispatcher.start(event_url)
         }));
     }
 
@@ -2,11 +2,14 @@ use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::sync::{LazyLock, RwLock};
 use std::thread;
+use std::time::Duration;
 
 use prometheus_client::encoding::EncodeLabelSet;
 use prometheus_client::encoding::text::encode;
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{Histogram, exponential_buckets};
 use prometheus_client::registry::{Metric, Registry};
 
 #[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
@@ -15,6 +18,11 @@ struct UdpLabels {
     r#type: String,
 }
 
+#[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
+struct ServerLabels {
+    server: String,
+}
+
 static REGISTRY: LazyLock<RwLock<Registry>> = LazyLock::new(|| RwLock::new(Registry::default()));
 static UDP_SENT_COUNT: LazyLock<Family<UdpLabels, Counter>> = LazyLock::new(|| {
     let counter = Family::<UdpLabels, Counter>::default();
@@ -53,6 +61,47 @@ static UDP_RECEIVED_BYTES: LazyLock<Family<UdpLabels, Counter>> = LazyLock::new(
     counter
 });
 
+static UDP_PUSH_ACK_RTT: LazyLock<Family<ServerLabels, Histogram>> = LazyLock::new(|| {
+    let histogram = Family::<ServerLabels, Histogram>::new_with_constructor(|| {
+        Histogram::new(exponential_buckets(0.005, 2.0, 12))
+    });
+    register(
+        "udp_push_ack_rtt_seconds",
+        "Round-trip time between sending PUSH_DATA and receiving PUSH_ACK",
+        histogram.clone(),
+    );
+    histogram
+});
+
+static SERVER_CONNECTED: LazyLock<Family<ServerLabels, Gauge>> = LazyLock::new(|| {
+    let gauge = Family::<ServerLabels, Gauge>::default();
+    register(
+        "server_connected",
+        "Whether the UDP socket(s) for the server are setup (1) or not (0)",
+        gauge.clone(),
+    );
+    gauge
+});
+static SERVER_CONNECT_ERRORS: LazyLock<Family<ServerLabels, Counter>> = LazyLock::new(|| {
+    let counter = Family::<ServerLabels, Counter>::default();
+    register(
+        "server_connect_errors",
+        "Number of failed attempts to setup the UDP socket(s) for the server",
+        counter.clone(),
+    );
+    counter
+});
+
+static EVENTS_DROPPED_COUNT: LazyLock<Family<ServerLabels, Counter>> = LazyLock::new(|| {
+    let counter = Family::<ServerLabels, Counter>::default();
+    register(
+        "events_dropped_count",
+        "Number of Concentratord events dropped because the forwarder fell behind",
+        counter.clone(),
+    );
+    counter
+});
+
 fn register(name: &str, help: &str, metric: impl Metric) {
     let mut registry_w = REGISTRY.write().unwrap();
     registry_w.register(name, help, metric)
@@ -110,6 +159,47 @@ pub fn incr_udp_received_bytes(server: &str, typ: &str, count: usize) {
         .inc_by(count.try_into().unwrap());
 }
 
+pub fn observe_udp_push_ack_rtt(server: &str, rtt: Duration) {
+    UDP_PUSH_ACK_RTT
+        .get_or_create(&ServerLabels {
+            server: server.to_string(),
+        })
+        .observe(rtt.as_secs_f64());
+}
+
+pub fn set_server_connected(server: &str, connected: bool) {
+    SERVER_CONNECTED
+        .get_or_create(&ServerLabels {
+            server: server.to_string(),
+        })
+        .set(connected as i64);
+}
+
+pub fn incr_server_connect_errors(server: &str) {
+    SERVER_CONNECT_ERRORS
+        .get_or_create(&ServerLabels {
+            server: server.to_string(),
+        })
+        .inc();
+}
+
+pub fn incr_events_dropped_count(server: &str) {
+    EVENTS_DROPPED_COUNT
+        .get_or_create(&ServerLabels {
+            server: server.to_string(),
+        })
+        .inc();
+}
+
+#[cfg(test)]
+pub fn get_events_dropped_count(server: &str) -> u64 {
+    EVENTS_DROPPED_COUNT
+        .get_or_create(&ServerLabels {
+            server: server.to_string(),
+        })
+        .get()
+}
+
 fn handle_request(stream: TcpStream) {
     handle_read(&stream);
     handle_write(stream);
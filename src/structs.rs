@@ -11,7 +11,33 @@ use serde_json::Value;
 
 use chirpstack_api::{gw, prost_types};
 
-const PROTOCOL_VERSION: u8 = 0x02;
+pub const PROTOCOL_VERSION_1: u8 = 0x01;
+pub const PROTOCOL_VERSION_2: u8 = 0x02;
+
+// GPS epoch (1980-01-06T00:00:00Z) as Unix timestamp.
+const GPS_EPOCH_UNIX: i64 = 315964800;
+
+// Unix timestamps at which a leap second was added (since the GPS epoch).
+const LEAP_SECONDS_UNIX: [i64; 18] = [
+    362793600,  // 1981-07-01
+    394329600,  // 1982-07-01
+    425865600,  // 1983-07-01
+    489024000,  // 1985-07-01
+    567993600,  // 1988-01-01
+    631152000,  // 1990-01-01
+    662688000,  // 1991-01-01
+    709948800,  // 1992-07-01
+    741484800,  // 1993-07-01
+    773020800,  // 1994-07-01
+    820454400,  // 1996-01-01
+    867715200,  // 1997-07-01
+    915148800,  // 1999-01-01
+    1136073600, // 2006-01-01
+    1230768000, // 2009-01-01
+    1341100800, // 2012-07-01
+    1435708800, // 2015-07-01
+    1483228800, // 2017-01-01
+];
 
 pub enum Crc {
     Ok,
@@ -32,9 +58,11 @@ impl Serialize for Crc {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Modulation {
     Lora,
     Fsk,
+    LrFhss,
 }
 
 impl Serialize for Modulation {
@@ -45,6 +73,7 @@ impl Serialize for Modulation {
         match self {
             Modulation::Lora => serializer.serialize_str("LORA"),
             Modulation::Fsk => serializer.serialize_str("FSK"),
+            Modulation::LrFhss => serializer.serialize_str("LR-FHSS"),
         }
     }
 }
@@ -58,6 +87,7 @@ impl<'de> Deserialize<'de> for Modulation {
         match s.as_str() {
             "LORA" => Ok(Modulation::Lora),
             "FSK" => Ok(Modulation::Fsk),
+            "LR-FHSS" => Ok(Modulation::LrFhss),
             _ => Err(D::Error::custom("unexpected value"))?,
         }
     }
@@ -66,6 +96,7 @@ impl<'de> Deserialize<'de> for Modulation {
 pub enum DataRate {
     Lora(u32, u32), // SF and BW (kHz)
     Fsk(u32),       // bitrate
+    LrFhss(u32),    // operating channel width (Hz)
 }
 
 impl Serialize for DataRate {
@@ -76,6 +107,9 @@ impl Serialize for DataRate {
         match self {
             DataRate::Lora(sf, bw) => serializer.serialize_str(&format!("SF{}BW{}", sf, bw / 1000)),
             DataRate::Fsk(bitrate) => serializer.serialize_u32(*bitrate),
+            DataRate::LrFhss(ocw) => {
+                serializer.serialize_str(&format!("M0CW{}", (ocw + 500) / 1000))
+            }
         }
     }
 }
@@ -124,6 +158,11 @@ pub enum CodeRate {
     LoRa4_6,
     LoRa4_7,
     LoRa4_8,
+    LrFhss3_8,
+    LrFhss2_6,
+    LrFhss1_4,
+    LrFhss1_6,
+    LrFhss5_6,
 }
 
 impl Serialize for CodeRate {
@@ -136,6 +175,11 @@ impl Serialize for CodeRate {
             CodeRate::LoRa4_6 => serializer.serialize_str("4/6"),
             CodeRate::LoRa4_7 => serializer.serialize_str("4/7"),
             CodeRate::LoRa4_8 => serializer.serialize_str("4/8"),
+            CodeRate::LrFhss3_8 => serializer.serialize_str("3/8"),
+            CodeRate::LrFhss2_6 => serializer.serialize_str("2/6"),
+            CodeRate::LrFhss1_4 => serializer.serialize_str("1/4"),
+            CodeRate::LrFhss1_6 => serializer.serialize_str("1/6"),
+            CodeRate::LrFhss5_6 => serializer.serialize_str("5/6"),
             _ => serializer.serialize_none(),
         }
     }
@@ -152,12 +196,18 @@ impl<'de> Deserialize<'de> for CodeRate {
             "4/6" => Ok(CodeRate::LoRa4_6),
             "4/7" => Ok(CodeRate::LoRa4_7),
             "4/8" => Ok(CodeRate::LoRa4_8),
+            "3/8" => Ok(CodeRate::LrFhss3_8),
+            "2/6" => Ok(CodeRate::LrFhss2_6),
+            "1/4" => Ok(CodeRate::LrFhss1_4),
+            "1/6" => Ok(CodeRate::LrFhss1_6),
+            "5/6" => Ok(CodeRate::LrFhss5_6),
             _ => Ok(CodeRate::Undefined),
         }
     }
 }
 
 pub struct PushData {
+    pub protocol_version: u8,
     pub random_token: u16,
     pub gateway_id: [u8; 8],
     pub payload: PushDataPayload,
@@ -165,21 +215,29 @@ pub struct PushData {
 
 impl PushData {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut b = Vec::new();
-
-        b.push(PROTOCOL_VERSION);
-        b.append(&mut self.random_token.to_be_bytes().to_vec());
-        b.push(0x00);
-        b.append(&mut self.gateway_id.to_vec());
+        let mut b =
+            PushData::header_bytes(self.protocol_version, self.random_token, self.gateway_id);
 
         let mut j = serde_json::to_vec(&self.payload).unwrap();
         b.append(&mut j);
 
         b
     }
+
+    // Returns the PUSH_DATA header, e.g. for re-sending a buffered payload.
+    pub fn header_bytes(protocol_version: u8, random_token: u16, gateway_id: [u8; 8]) -> Vec<u8> {
+        let mut b = Vec::new();
+
+        b.push(protocol_version);
+        b.append(&mut random_token.to_be_bytes().to_vec());
+        b.push(0x00);
+        b.append(&mut gateway_id.to_vec());
+
+        b
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub struct PushDataPayload {
     pub rxpk: Vec<RxPk>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -196,21 +254,33 @@ pub struct RxPk {
     pub tmms: Option<u64>,
     /// Internal timestamp of "RX finished" event (32b unsigned)
     pub tmst: u32,
+    /// Fine timestamp, number of nanoseconds since last PPS [0..999999999]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ftime: Option<u32>,
+    /// AES key index used for encrypting the fine timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aesk: Option<u32>,
     /// RX central frequency in MHz (unsigned float, Hz precision)
     pub freq: f64,
     /// Concentrator "IF" channel used for RX (unsigned integer)
     pub chan: u32,
     /// Concentrator "RF chain" used for RX (unsigned integer)
     pub rfch: u32,
+    /// Concentrator board used for RX (unsigned integer)
+    pub brd: u32,
     /// Crc status: 1 = OK, -1 = fail, 0 = no Crc
     pub stat: Crc,
-    /// Modulation identifier "LORA" or "FSK"
+    /// Modulation identifier "LORA", "FSK" or "LR-FHSS"
     pub modu: Modulation,
-    /// LoRa datarate identifier (eg. SF12BW500)}
+    /// LoRa datarate identifier (eg. SF12BW500), FSK bitrate or LR-FHSS
+    /// datarate identifier (eg. M0CW137)
     pub datr: DataRate,
-    /// LoRa coding rate.
+    /// LoRa or LR-FHSS coding rate.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub codr: Option<CodeRate>,
+    /// LR-FHSS hopping grid number of steps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hpw: Option<u32>,
     /// RSSI in dBm (signed integer, 1 dB precision).
     pub rssi: i32,
     /// Lora SNR ratio in dB (signed float, 0.1 dB precision).
@@ -220,6 +290,9 @@ pub struct RxPk {
     pub size: u8,
     /// Base64 encoded RF packet payload, padded.
     pub data: String,
+    /// Per antenna / RF chain signal information.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rsig: Vec<RSig>,
 }
 
 impl RxPk {
@@ -238,6 +311,29 @@ impl RxPk {
             }
         };
 
+        let ftime = rx_info
+            .fine_time_since_gps_epoch
+            .as_ref()
+            .map(|v| v.nanos as u32);
+
+        let encrypted_fine_timestamp = match &up.rx_info_legacy {
+            Some(v) => match &v.fine_timestamp {
+                Some(gw::uplink_rx_info_legacy::FineTimestamp::EncryptedFineTimestamp(v)) => {
+                    Some(v)
+                }
+                _ => None,
+            },
+            None => None,
+        };
+
+        let lsnr = match &tx_info.modulation {
+            Some(v) => match &v.parameters {
+                Some(gw::modulation::Parameters::Lora(_)) => Some(rx_info.snr),
+                _ => None,
+            },
+            None => None,
+        };
+
         Ok(RxPk {
             time: match &rx_info.gw_time {
                 Some(v) => match TryInto::<SystemTime>::try_into(*v) {
@@ -255,9 +351,12 @@ impl RxPk {
                 bytes.copy_from_slice(&rx_info.context);
                 u32::from_be_bytes(bytes)
             },
+            ftime,
+            aesk: encrypted_fine_timestamp.map(|v| v.aes_key_index),
             freq: tx_info.frequency as f64 / 1000000.0,
             chan: rx_info.channel,
             rfch: rx_info.rf_chain,
+            brd: rx_info.board,
             stat: match rx_info.crc_status() {
                 gw::CrcStatus::CrcOk => Crc::Ok,
                 gw::CrcStatus::BadCrc => Crc::Invalid,
@@ -268,9 +367,7 @@ impl RxPk {
                     Some(v) => match &v {
                         gw::modulation::Parameters::Lora(_) => Modulation::Lora,
                         gw::modulation::Parameters::Fsk(_) => Modulation::Fsk,
-                        gw::modulation::Parameters::LrFhss(_) => {
-                            return Err(anyhow!("unsupported modulation"));
-                        }
+                        gw::modulation::Parameters::LrFhss(_) => Modulation::LrFhss,
                     },
                     None => {
                         return Err(anyhow!("parameters must not be None"));
@@ -287,8 +384,8 @@ impl RxPk {
                             DataRate::Lora(v.spreading_factor, v.bandwidth)
                         }
                         gw::modulation::Parameters::Fsk(v) => DataRate::Fsk(v.datarate),
-                        gw::modulation::Parameters::LrFhss(_) => {
-                            return Err(anyhow!("unsupported modulation"));
+                        gw::modulation::Parameters::LrFhss(v) => {
+                            DataRate::LrFhss(v.operating_channel_width)
                         }
                     },
                     None => {
@@ -308,24 +405,61 @@ impl RxPk {
                         gw::CodeRate::Cr48 => CodeRate::LoRa4_8,
                         _ => CodeRate::Undefined,
                     }),
+                    Some(gw::modulation::Parameters::LrFhss(v)) => Some(match v.code_rate() {
+                        gw::CodeRate::Cr38 => CodeRate::LrFhss3_8,
+                        gw::CodeRate::Cr26 => CodeRate::LrFhss2_6,
+                        gw::CodeRate::Cr14 => CodeRate::LrFhss1_4,
+                        gw::CodeRate::Cr16 => CodeRate::LrFhss1_6,
+                        gw::CodeRate::Cr56 => CodeRate::LrFhss5_6,
+                        _ => CodeRate::Undefined,
+                    }),
                     _ => None,
                 },
                 None => None,
             },
-            rssi: rx_info.rssi,
-            lsnr: match &tx_info.modulation {
+            hpw: match &tx_info.modulation {
                 Some(v) => match &v.parameters {
-                    Some(gw::modulation::Parameters::Lora(_)) => Some(rx_info.snr),
+                    Some(gw::modulation::Parameters::LrFhss(v)) => Some(v.grid_steps),
                     _ => None,
                 },
                 None => None,
             },
+            rssi: rx_info.rssi,
+            lsnr,
             size: up.phy_payload.len() as u8,
             data: general_purpose::STANDARD.encode(up.phy_payload.clone()),
+            rsig: vec![RSig {
+                ant: rx_info.antenna,
+                chan: rx_info.channel,
+                rssic: rx_info.rssi,
+                lsnr,
+                etime: encrypted_fine_timestamp
+                    .map(|v| general_purpose::STANDARD.encode(&v.encrypted_ns)),
+                ftime,
+            }],
         })
     }
 }
 
+#[derive(Serialize)]
+pub struct RSig {
+    /// Antenna number on which the signal has been received.
+    pub ant: u32,
+    /// Concentrator "IF" channel used for RX (unsigned integer).
+    pub chan: u32,
+    /// RSSI in dBm of the channel (signed integer, 1 dB precision).
+    pub rssic: i32,
+    /// Lora SNR ratio in dB (signed float, 0.1 dB precision).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lsnr: Option<f32>,
+    /// Base64 encoded encrypted fine timestamp, ns precision [0..999999999].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etime: Option<String>,
+    /// Fine timestamp, ns precision [0..999999999].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ftime: Option<u32>,
+}
+
 #[derive(Serialize)]
 pub struct Stat {
     /// UTC 'system' time of the gateway, ISO 8601 'expanded' format.
@@ -388,15 +522,15 @@ pub struct PushAck {
 }
 
 impl PushAck {
-    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+    pub fn from_bytes(b: &[u8], protocol_version: u8) -> Result<Self> {
         if b.len() != 4 {
             return Err(anyhow!("expected 4 bytes, got: {}", b.len()));
         }
 
-        if b[0] != PROTOCOL_VERSION {
+        if b[0] != protocol_version {
             return Err(anyhow!(
                 "expected protocol version: {}, got: {}",
-                PROTOCOL_VERSION,
+                protocol_version,
                 b[0]
             ));
         }
@@ -415,6 +549,7 @@ impl PushAck {
 }
 
 pub struct PullData {
+    pub protocol_version: u8,
     pub random_token: u16,
     pub gateway_id: [u8; 8],
 }
@@ -422,7 +557,7 @@ pub struct PullData {
 impl PullData {
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut b: Vec<u8> = Vec::with_capacity(12);
-        b.push(PROTOCOL_VERSION);
+        b.push(self.protocol_version);
         b.append(&mut self.random_token.to_be_bytes().to_vec());
         b.push(0x02);
         b.append(&mut self.gateway_id.to_vec());
@@ -436,15 +571,15 @@ pub struct PullAck {
 }
 
 impl PullAck {
-    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+    pub fn from_bytes(b: &[u8], protocol_version: u8) -> Result<Self> {
         if b.len() != 4 {
             return Err(anyhow!("expected 4 bytes, got: {}", b.len()));
         }
 
-        if b[0] != PROTOCOL_VERSION {
+        if b[0] != protocol_version {
             return Err(anyhow!(
                 "expected protocol version: {}, got: {}",
-                PROTOCOL_VERSION,
+                protocol_version,
                 b[0]
             ));
         }
@@ -468,15 +603,15 @@ pub struct PullResp {
 }
 
 impl PullResp {
-    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+    pub fn from_bytes(b: &[u8], protocol_version: u8) -> Result<Self> {
         if b.len() < 5 {
             return Err(anyhow!("expected at least 5 bytes, got: {}", b.len()));
         }
 
-        if b[0] != PROTOCOL_VERSION {
+        if b[0] != protocol_version {
             return Err(anyhow!(
                 "expected protocol version: {}, got: {}",
-                PROTOCOL_VERSION,
+                protocol_version,
                 b[0]
             ));
         }
@@ -510,6 +645,9 @@ pub struct TxPk {
     pub tmst: Option<u32>,
     /// Send packet at a certain GPS time (GPS synchronization required).
     pub tmms: Option<u64>,
+    /// Send packet at a certain UTC time, ISO 8601 'compact' format (GPS
+    /// synchronization required).
+    pub time: Option<String>,
     /// TX central frequency in MHz (unsigned float, Hz precision).
     pub freq: f64,
     /// TX output power in dBm (unsigned integer, dBm precision).
@@ -537,6 +675,8 @@ impl TxPk {
         &self,
         downlink_id: u32,
         gateway_id: Vec<u8>,
+        board: u32,
+        antenna: u32,
     ) -> Result<chirpstack_api::gw::DownlinkFrame> {
         let tx_info = chirpstack_api::gw::DownlinkTxInfo {
             frequency: (self.freq * 1_000_000.0) as u32,
@@ -554,6 +694,9 @@ impl TxPk {
                                     Some(CodeRate::LoRa4_7) => gw::CodeRate::Cr47,
                                     Some(CodeRate::LoRa4_8) => gw::CodeRate::Cr48,
                                     Some(CodeRate::Undefined) | None => gw::CodeRate::CrUndefined,
+                                    Some(_) => {
+                                        return Err(anyhow!("LoRa CodeRate expected"));
+                                    }
                                 }
                                 .into(),
                                 polarization_inversion: self.ipol.unwrap_or(true),
@@ -577,10 +720,13 @@ impl TxPk {
                             return Err(anyhow!("FSK DataRate expected"));
                         }
                     },
+                    Modulation::LrFhss => {
+                        return Err(anyhow!("LR-FHSS downlink is not supported"));
+                    }
                 }),
             }),
-            board: 0,
-            antenna: 0,
+            board,
+            antenna,
             timing: Some(gw::Timing {
                 parameters: Some(if self.imme.unwrap_or(false) {
                     gw::timing::Parameters::Immediately(gw::ImmediatelyTimingInfo {})
@@ -597,6 +743,17 @@ impl TxPk {
                     gw::timing::Parameters::GpsEpoch(gw::GpsEpochTimingInfo {
                         time_since_gps_epoch: Some(Duration::from_millis(v).try_into()?),
                     })
+                } else if let Some(v) = &self.time {
+                    let time = match DateTime::parse_from_rfc3339(v) {
+                        Ok(v) => v.with_timezone(&Utc),
+                        Err(err) => {
+                            return Err(anyhow!("parse time error: {}", err));
+                        }
+                    };
+
+                    gw::timing::Parameters::GpsEpoch(gw::GpsEpochTimingInfo {
+                        time_since_gps_epoch: Some(time_since_gps_epoch(&time)?.try_into()?),
+                    })
                 } else {
                     return Err(anyhow!("no timing information found"));
                 }),
@@ -626,6 +783,7 @@ impl TxPk {
 }
 
 pub struct TxAck {
+    pub protocol_version: u8,
     pub random_token: u16,
     pub gateway_id: [u8; 8],
     pub payload: TxAckPayload,
@@ -635,7 +793,7 @@ impl TxAck {
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut b = Vec::new();
 
-        b.push(PROTOCOL_VERSION);
+        b.push(self.protocol_version);
         b.append(&mut self.random_token.to_be_bytes().to_vec());
         b.push(0x05);
         b.append(&mut self.gateway_id.to_vec());
@@ -657,6 +815,22 @@ pub struct TxAckPayloadError {
     pub error: String,
 }
 
+// Convert the given UTC time to the duration since GPS epoch. As GPS time does not
+// have leap seconds, the leap seconds since the GPS epoch are added.
+fn time_since_gps_epoch(time: &DateTime<Utc>) -> Result<Duration> {
+    let unix = time.timestamp();
+    if unix < GPS_EPOCH_UNIX {
+        return Err(anyhow!("time is before GPS epoch"));
+    }
+
+    let leap_seconds = LEAP_SECONDS_UNIX.iter().filter(|v| unix >= **v).count() as u64;
+
+    Ok(Duration::new(
+        (unix - GPS_EPOCH_UNIX) as u64 + leap_seconds,
+        time.timestamp_subsec_nanos(),
+    ))
+}
+
 // see: https://serde.rs/custom-date-format.html
 mod expanded_time_format {
     use chrono::{DateTime, Utc};
@@ -701,7 +875,7 @@ mod tests {
     fn test_push_data_rxpk_lora() {
         let rx_info = gw::UplinkRxInfo {
             gateway_id: "0102030405060708".into(),
-            gw_time: Some(SystemTime::UNIX_EPOCH.try_into().unwrap()),
+            gw_time: Some(SystemTime::UNIX_EPOCH.into()),
             time_since_gps_epoch: Some(Duration::from_secs(1).try_into().unwrap()),
             rssi: -160,
             snr: 5.5,
@@ -736,6 +910,7 @@ mod tests {
 
         let rxpk = RxPk::from_proto(&uf).unwrap();
         let pd = PushData {
+            protocol_version: PROTOCOL_VERSION_2,
             random_token: 123,
             gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
             payload: PushDataPayload {
@@ -752,7 +927,7 @@ mod tests {
 
         assert_eq!(
             str::from_utf8(&b[12..]).unwrap(),
-            r#"{"rxpk":[{"time":"1970-01-01T00:00:00+00:00","tmms":1000,"tmst":16909060,"freq":868.3,"chan":1,"rfch":1,"stat":1,"modu":"LORA","datr":"SF12BW125","codr":"4/5","rssi":-160,"lsnr":5.5,"size":3,"data":"AQID"}]}"#
+            r#"{"rxpk":[{"time":"1970-01-01T00:00:00+00:00","tmms":1000,"tmst":16909060,"freq":868.3,"chan":1,"rfch":1,"brd":2,"stat":1,"modu":"LORA","datr":"SF12BW125","codr":"4/5","rssi":-160,"lsnr":5.5,"size":3,"data":"AQID","rsig":[{"ant":3,"chan":1,"rssic":-160,"lsnr":5.5}]}]}"#
         );
     }
 
@@ -760,7 +935,7 @@ mod tests {
     fn test_push_data_rxpk_fsk() {
         let rx_info = gw::UplinkRxInfo {
             gateway_id: "0102030405060708".into(),
-            gw_time: Some(SystemTime::UNIX_EPOCH.try_into().unwrap()),
+            gw_time: Some(SystemTime::UNIX_EPOCH.into()),
             time_since_gps_epoch: Some(Duration::from_secs(1).try_into().unwrap()),
             rssi: -160,
             channel: 1,
@@ -791,6 +966,7 @@ mod tests {
 
         let rxpk = RxPk::from_proto(&uf).unwrap();
         let pd = PushData {
+            protocol_version: PROTOCOL_VERSION_2,
             random_token: 123,
             gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
             payload: PushDataPayload {
@@ -807,7 +983,120 @@ mod tests {
 
         assert_eq!(
             str::from_utf8(&b[12..]).unwrap(),
-            r#"{"rxpk":[{"time":"1970-01-01T00:00:00+00:00","tmms":1000,"tmst":16909060,"freq":868.3,"chan":1,"rfch":2,"stat":1,"modu":"FSK","datr":50000,"rssi":-160,"size":3,"data":"AQID"}]}"#
+            r#"{"rxpk":[{"time":"1970-01-01T00:00:00+00:00","tmms":1000,"tmst":16909060,"freq":868.3,"chan":1,"rfch":2,"brd":3,"stat":1,"modu":"FSK","datr":50000,"rssi":-160,"size":3,"data":"AQID","rsig":[{"ant":4,"chan":1,"rssic":-160}]}]}"#
+        );
+    }
+
+    #[test]
+    fn test_push_data_rxpk_fine_timestamp() {
+        let rx_info = gw::UplinkRxInfo {
+            gateway_id: "0102030405060708".into(),
+            gw_time: Some(SystemTime::UNIX_EPOCH.into()),
+            time_since_gps_epoch: Some(Duration::from_secs(1).try_into().unwrap()),
+            fine_time_since_gps_epoch: Some(
+                Duration::from_nanos(1_000_012_345).try_into().unwrap(),
+            ),
+            rssi: -160,
+            snr: 5.5,
+            channel: 1,
+            rf_chain: 1,
+            context: vec![1, 2, 3, 4],
+            crc_status: gw::CrcStatus::CrcOk.into(),
+            ..Default::default()
+        };
+
+        let tx_info = gw::UplinkTxInfo {
+            frequency: 868300000,
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::Lora(gw::LoraModulationInfo {
+                    bandwidth: 125000,
+                    spreading_factor: 12,
+                    code_rate: gw::CodeRate::Cr45.into(),
+                    ..Default::default()
+                })),
+            }),
+        };
+
+        let rx_info_legacy = gw::UplinkRxInfoLegacy {
+            fine_timestamp_type: gw::FineTimestampType::Encrypted.into(),
+            fine_timestamp: Some(
+                gw::uplink_rx_info_legacy::FineTimestamp::EncryptedFineTimestamp(
+                    gw::EncryptedFineTimestamp {
+                        aes_key_index: 2,
+                        encrypted_ns: vec![4, 5, 6],
+                        ..Default::default()
+                    },
+                ),
+            ),
+            ..Default::default()
+        };
+
+        let uf = gw::UplinkFrame {
+            rx_info: Some(rx_info),
+            tx_info: Some(tx_info),
+            rx_info_legacy: Some(rx_info_legacy),
+            phy_payload: vec![1, 2, 3],
+            ..Default::default()
+        };
+
+        let rxpk = RxPk::from_proto(&uf).unwrap();
+        assert_eq!(
+            serde_json::to_string(&rxpk).unwrap(),
+            r#"{"time":"1970-01-01T00:00:00+00:00","tmms":1000,"tmst":16909060,"ftime":12345,"aesk":2,"freq":868.3,"chan":1,"rfch":1,"brd":0,"stat":1,"modu":"LORA","datr":"SF12BW125","codr":"4/5","rssi":-160,"lsnr":5.5,"size":3,"data":"AQID","rsig":[{"ant":0,"chan":1,"rssic":-160,"lsnr":5.5,"etime":"BAUG","ftime":12345}]}"#
+        );
+    }
+
+    #[test]
+    fn test_push_data_rxpk_lr_fhss() {
+        let rx_info = gw::UplinkRxInfo {
+            gateway_id: "0102030405060708".into(),
+            gw_time: Some(SystemTime::UNIX_EPOCH.into()),
+            time_since_gps_epoch: Some(Duration::from_secs(1).try_into().unwrap()),
+            rssi: -120,
+            snr: 3.5,
+            channel: 8,
+            rf_chain: 0,
+            context: vec![1, 2, 3, 4],
+            crc_status: gw::CrcStatus::CrcOk.into(),
+            ..Default::default()
+        };
+
+        let tx_info = gw::UplinkTxInfo {
+            frequency: 868100000,
+            modulation: Some(gw::Modulation {
+                parameters: Some(gw::modulation::Parameters::LrFhss(
+                    gw::LrFhssModulationInfo {
+                        operating_channel_width: 136719,
+                        code_rate: gw::CodeRate::Cr26.into(),
+                        grid_steps: 52,
+                        ..Default::default()
+                    },
+                )),
+            }),
+        };
+
+        let uf = gw::UplinkFrame {
+            rx_info: Some(rx_info),
+            tx_info: Some(tx_info),
+            phy_payload: vec![1, 2, 3],
+            ..Default::default()
+        };
+
+        let rxpk = RxPk::from_proto(&uf).unwrap();
+        let pd = PushData {
+            protocol_version: PROTOCOL_VERSION_2,
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            payload: PushDataPayload {
+                rxpk: vec![rxpk],
+                stat: None,
+            },
+        };
+
+        let b = pd.to_bytes();
+        assert_eq!(
+            str::from_utf8(&b[12..]).unwrap(),
+            r#"{"rxpk":[{"time":"1970-01-01T00:00:00+00:00","tmms":1000,"tmst":16909060,"freq":868.1,"chan":8,"rfch":0,"brd":0,"stat":1,"modu":"LR-FHSS","datr":"M0CW137","codr":"2/6","hpw":52,"rssi":-120,"size":3,"data":"AQID","rsig":[{"ant":0,"chan":8,"rssic":-120}]}]}"#
         );
     }
 
@@ -815,7 +1104,7 @@ mod tests {
     fn test_push_data_stat() {
         let gs = gw::GatewayStats {
             gateway_id: "0102030405060708".into(),
-            time: Some(SystemTime::UNIX_EPOCH.try_into().unwrap()),
+            time: Some(SystemTime::UNIX_EPOCH.into()),
             location: Some(common::Location {
                 latitude: 1.123,
                 longitude: 2.123,
@@ -831,6 +1120,7 @@ mod tests {
 
         let stat = Stat::from_proto(&gs).unwrap();
         let pd = PushData {
+            protocol_version: PROTOCOL_VERSION_2,
             random_token: 123,
             gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
             payload: PushDataPayload {
@@ -855,13 +1145,14 @@ mod tests {
     fn test_push_ack() {
         let b: [u8; 4] = [2, 0, 123, 1];
 
-        let push_ack = PushAck::from_bytes(&b).unwrap();
+        let push_ack = PushAck::from_bytes(&b, PROTOCOL_VERSION_2).unwrap();
         assert_eq!(push_ack.random_token, 123);
     }
 
     #[test]
     fn test_pull_data() {
         let pull_data = PullData {
+            protocol_version: PROTOCOL_VERSION_2,
             random_token: 123,
             gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
         };
@@ -870,11 +1161,33 @@ mod tests {
         assert_eq!(b, [2, 0, 123, 2, 1, 2, 3, 4, 5, 6, 7, 8]);
     }
 
+    #[test]
+    fn test_pull_data_v1() {
+        let pull_data = PullData {
+            protocol_version: PROTOCOL_VERSION_1,
+            random_token: 123,
+            gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+
+        let b = pull_data.to_bytes();
+        assert_eq!(b, [1, 0, 123, 2, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_pull_ack_v1() {
+        let b: [u8; 4] = [1, 0, 123, 4];
+
+        let pull_ack = PullAck::from_bytes(&b, PROTOCOL_VERSION_1).unwrap();
+        assert_eq!(pull_ack.random_token, 123);
+
+        assert!(PullAck::from_bytes(&b, PROTOCOL_VERSION_2).is_err());
+    }
+
     #[test]
     fn test_pull_ack() {
         let b: [u8; 4] = [2, 0, 123, 4];
 
-        let pull_ack = PullAck::from_bytes(&b).unwrap();
+        let pull_ack = PullAck::from_bytes(&b, PROTOCOL_VERSION_2).unwrap();
         assert_eq!(pull_ack.random_token, 123);
     }
 
@@ -896,14 +1209,14 @@ mod tests {
         let mut b: Vec<u8> = vec![2, 0, 123, 3];
         b.append(&mut txpk);
 
-        let pull_resp = PullResp::from_bytes(&b).unwrap();
+        let pull_resp = PullResp::from_bytes(&b, PROTOCOL_VERSION_2).unwrap();
 
         assert_eq!(pull_resp.random_token, 123);
 
         let downlink_frame = pull_resp
             .payload
             .txpk
-            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8], 0, 0)
             .unwrap();
 
         let tx_info = gw::DownlinkTxInfo {
@@ -926,7 +1239,6 @@ mod tests {
                     ..Default::default()
                 })),
             }),
-            ..Default::default()
         };
 
         assert_eq!(
@@ -964,14 +1276,14 @@ mod tests {
         let mut b: Vec<u8> = vec![2, 0, 123, 3];
         b.append(&mut txpk);
 
-        let pull_resp = PullResp::from_bytes(&b).unwrap();
+        let pull_resp = PullResp::from_bytes(&b, PROTOCOL_VERSION_2).unwrap();
 
         assert_eq!(pull_resp.random_token, 123);
 
         let downlink_frame = pull_resp
             .payload
             .txpk
-            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8], 0, 0)
             .unwrap();
 
         let tx_info = gw::DownlinkTxInfo {
@@ -994,7 +1306,6 @@ mod tests {
                     ..Default::default()
                 })),
             }),
-            ..Default::default()
         };
 
         assert_eq!(
@@ -1032,14 +1343,14 @@ mod tests {
         let mut b: Vec<u8> = vec![2, 0, 123, 3];
         b.append(&mut txpk);
 
-        let pull_resp = PullResp::from_bytes(&b).unwrap();
+        let pull_resp = PullResp::from_bytes(&b, PROTOCOL_VERSION_2).unwrap();
 
         assert_eq!(pull_resp.random_token, 123);
 
         let downlink_frame = pull_resp
             .payload
             .txpk
-            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8], 0, 0)
             .unwrap();
 
         let tx_info = gw::DownlinkTxInfo {
@@ -1062,7 +1373,6 @@ mod tests {
                     ..Default::default()
                 })),
             }),
-            ..Default::default()
         };
 
         assert_eq!(
@@ -1082,6 +1392,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pull_resp_lora_time() {
+        let txpk = r#"{"txpk":{
+            "freq":864.123456,
+            "rfch":0,
+            "powe":14,
+            "modu":"LORA",
+            "datr":"SF11BW125",
+            "codr":"4/5",
+            "ipol":false,
+            "size":32,
+            "time":"2019-01-01T00:00:00.5Z",
+            "data":"H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s="}}"#;
+        let mut txpk = txpk.as_bytes().to_vec();
+
+        let mut b: Vec<u8> = vec![2, 0, 123, 3];
+        b.append(&mut txpk);
+
+        let pull_resp = PullResp::from_bytes(&b, PROTOCOL_VERSION_2).unwrap();
+        let downlink_frame = pull_resp
+            .payload
+            .txpk
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8], 0, 0)
+            .unwrap();
+
+        // 2019-01-01T00:00:00Z == 1230336018 seconds since GPS epoch (18 leap seconds).
+        assert_eq!(
+            downlink_frame.items[0].tx_info.as_ref().unwrap().timing,
+            Some(gw::Timing {
+                parameters: Some(gw::timing::Parameters::GpsEpoch(gw::GpsEpochTimingInfo {
+                    time_since_gps_epoch: Some(
+                        Duration::from_millis(1230336018500).try_into().unwrap()
+                    ),
+                })),
+            })
+        );
+    }
+
+    #[test]
+    fn test_time_since_gps_epoch() {
+        let tests = vec![
+            ("1980-01-06T00:00:00Z", Duration::from_secs(0)),
+            ("1981-06-30T23:59:59Z", Duration::from_secs(46828799)),
+            ("1981-07-01T00:00:00Z", Duration::from_secs(46828801)),
+            ("2017-01-01T00:00:00Z", Duration::from_secs(1167264018)),
+        ];
+
+        for (time, expected) in tests {
+            let time = DateTime::parse_from_rfc3339(time)
+                .unwrap()
+                .with_timezone(&Utc);
+            assert_eq!(expected, time_since_gps_epoch(&time).unwrap());
+        }
+
+        let time = DateTime::parse_from_rfc3339("1970-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert!(time_since_gps_epoch(&time).is_err());
+    }
+
     #[test]
     fn test_pull_resp_fsk_delay() {
         let txpk = r#"{"txpk":{
@@ -1099,14 +1469,14 @@ mod tests {
         let mut b: Vec<u8> = vec![2, 0, 123, 3];
         b.append(&mut txpk);
 
-        let pull_resp = PullResp::from_bytes(&b).unwrap();
+        let pull_resp = PullResp::from_bytes(&b, PROTOCOL_VERSION_2).unwrap();
 
         assert_eq!(pull_resp.random_token, 123);
 
         let downlink_frame = pull_resp
             .payload
             .txpk
-            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8], 0, 0)
             .unwrap();
 
         let tx_info = gw::DownlinkTxInfo {
@@ -1126,7 +1496,6 @@ mod tests {
                     datarate: 50000,
                 })),
             }),
-            ..Default::default()
         };
 
         assert_eq!(
@@ -1146,9 +1515,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pull_resp_board_antenna() {
+        let txpk = r#"{"txpk":{
+            "imme":true,
+            "freq":869.525,
+            "rfch":0,
+            "powe":14,
+            "modu":"LORA",
+            "datr":"SF12BW125",
+            "codr":"4/5",
+            "ipol":true,
+            "size":32,
+            "data":"H3P3N2i9qc4yt7rK7ldqoeCVJGBybzPY5h1Dd7P7p8s="}}"#;
+        let mut txpk = txpk.as_bytes().to_vec();
+
+        let mut b: Vec<u8> = vec![2, 0, 123, 3];
+        b.append(&mut txpk);
+
+        let pull_resp = PullResp::from_bytes(&b, PROTOCOL_VERSION_2).unwrap();
+
+        // The downlink is sent using the board and antenna of the (virtual) gateway.
+        let downlink_frame = pull_resp
+            .payload
+            .txpk
+            .to_proto(0, vec![1, 2, 3, 4, 5, 6, 7, 8], 1, 2)
+            .unwrap();
+
+        let tx_info = downlink_frame.items[0].tx_info.as_ref().unwrap();
+        assert_eq!(1, tx_info.board);
+        assert_eq!(2, tx_info.antenna);
+    }
+
     #[test]
     fn test_tx_ack() {
         let tx_ack = TxAck {
+            protocol_version: PROTOCOL_VERSION_2,
             random_token: 123,
             gateway_id: [1, 2, 3, 4, 5, 6, 7, 8],
             payload: TxAckPayload {
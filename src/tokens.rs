@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use super::buffer;
+
+// In-flight PUSH_DATA tokens, used for matching the PUSH_ACK responses to the sent
+// PUSH_DATA and for calculating the PUSH_ACK RTT and acknowledgement ratio.
+pub struct PushDataTokens {
+    window: usize,
+    last: u16,
+    tokens: VecDeque<(u16, Instant)>,
+    sent: u32,
+    acked: u32,
+}
+
+impl PushDataTokens {
+    pub fn new(window: usize) -> Self {
+        PushDataTokens {
+            window,
+            last: rand::rng().random(),
+            tokens: VecDeque::new(),
+            sent: 0,
+            acked: 0,
+        }
+    }
+
+    // Allocate and add the token for a new PUSH_DATA, skipping the in-flight tokens
+    // and the tokens for which in_use returns true (e.g. buffered datagrams).
+    pub fn next(&mut self, in_use: impl Fn(u16) -> bool) -> u16 {
+        self.last = buffer::next_token(self.last, |v| self.contains(v) || in_use(v));
+        self.add(self.last);
+        self.last
+    }
+
+    // Add the token of a sent PUSH_DATA. In case the window is full, the oldest
+    // token is dropped.
+    pub fn add(&mut self, token: u16) {
+        self.tokens.retain(|(t, _)| *t != token);
+        if self.tokens.len() >= self.window {
+            self.tokens.pop_front();
+        }
+        self.tokens.push_back((token, Instant::now()));
+        self.sent += 1;
+    }
+
+    pub fn contains(&self, token: u16) -> bool {
+        self.tokens.iter().any(|(t, _)| *t == token)
+    }
+
+    // Remove the given token from the in-flight tokens and return the duration since
+    // the PUSH_DATA was sent. Returns None if the token was not found.
+    pub fn ack(&mut self, token: u16) -> Option<Duration> {
+        let i = self.tokens.iter().position(|(t, _)| *t == token)?;
+        self.acked += 1;
+        self.tokens.remove(i).map(|(_, sent_at)| sent_at.elapsed())
+    }
+
+    // Returns the percentage of the PUSH_DATA acknowledged since the last call, or
+    // None in case no PUSH_DATA was sent.
+    pub fn get_and_reset_ackr(&mut self) -> Option<f32> {
+        let (sent, acked) = (self.sent, self.acked);
+        self.sent = 0;
+        self.acked = 0;
+
+        match sent {
+            0 => None,
+            _ => Some(acked as f32 / sent as f32 * 100.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_ack_out_of_order() {
+        let mut t = PushDataTokens::new(64);
+        t.add(1);
+        t.add(2);
+        t.add(3);
+
+        assert!(t.ack(3).is_some());
+        assert!(t.ack(1).is_some());
+        assert!(t.ack(3).is_none());
+        assert!(t.ack(4).is_none());
+        assert!(t.ack(2).is_some());
+    }
+
+    #[test]
+    fn test_window() {
+        let mut t = PushDataTokens::new(2);
+        t.add(1);
+        t.add(2);
+        t.add(3);
+
+        // The oldest token was evicted.
+        assert!(t.ack(1).is_none());
+        assert!(t.ack(2).is_some());
+        assert!(t.ack(3).is_some());
+    }
+
+    #[test]
+    fn test_rtt() {
+        let mut t = PushDataTokens::new(64);
+        t.add(1);
+        thread::sleep(Duration::from_millis(10));
+
+        assert!(t.ack(1).unwrap() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_ackr() {
+        let mut t = PushDataTokens::new(64);
+        assert_eq!(None, t.get_and_reset_ackr());
+
+        t.add(1);
+        t.add(2);
+        t.ack(1);
+        t.add(3);
+        t.ack(3);
+        t.ack(4);
+        assert_eq!(Some(2.0 / 3.0 * 100.0), t.get_and_reset_ackr());
+
+        // The ack of a PUSH_DATA sent in the previous interval.
+        t.add(5);
+        t.ack(2);
+        t.ack(5);
+        assert_eq!(Some(200.0), t.get_and_reset_ackr());
+        assert_eq!(None, t.get_and_reset_ackr());
+    }
+
+    #[test]
+    fn test_next() {
+        let mut t = PushDataTokens::new(64);
+        t.last = 0;
+
+        assert_eq!(1, t.next(|_| false));
+        assert_eq!(3, t.next(|v| v == 2));
+
+        // In-flight tokens are skipped.
+        t.last = 0;
+        assert_eq!(2, t.next(|_| false));
+        assert_eq!(4, t.next(|_| false));
+    }
+}
@@ -0,0 +1,247 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::config::{IpPreference, Server};
+
+// Resolve the given server (hostname:port) and return a UDP socket connected to
+// the first resolved address to which a connection could be made. The socket is
+// bound to the address family matching the resolved address, unless a bind
+// address has been configured. The given failed address (e.g. the address that
+// stopped responding to keepalive frames) is only tried as last resort.
+pub fn connect(
+    conf: &Server,
+    server: &str,
+    bind_port: u16,
+    failed_addr: Option<SocketAddr>,
+) -> Result<UdpSocket> {
+    let bind_ip: Option<IpAddr> = match conf.bind_address.as_str() {
+        "" => None,
+        v => Some(
+            v.parse()
+                .map_err(|e| anyhow!("parse bind_address error: {}", e))?,
+        ),
+    };
+
+    let addrs = match server.to_socket_addrs() {
+        Ok(v) => move_to_end(sort_addrs(v.collect(), conf.ip_preference), failed_addr),
+        Err(err) => return Err(anyhow!("resolve server address error: {}", err)),
+    };
+
+    if addrs.is_empty() {
+        return Err(anyhow!("server address did not resolve to any address"));
+    }
+
+    let mut last_err = None;
+    for addr in addrs {
+        match connect_addr(conf, bind_ip, bind_port, addr) {
+            Ok(v) => {
+                debug!("Connected UDP socket, server: {}, addr: {}", server, addr);
+                return Ok(v);
+            }
+            Err(err) => {
+                warn!(
+                    "Connect UDP socket error: {}, server: {}, addr: {}",
+                    err, server, addr
+                );
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "connect udp socket error: {}",
+        last_err.map(|v| v.to_string()).unwrap_or_default()
+    ))
+}
+
+fn connect_addr(
+    conf: &Server,
+    bind_ip: Option<IpAddr>,
+    bind_port: u16,
+    addr: SocketAddr,
+) -> Result<UdpSocket> {
+    let bind_ip = match (bind_ip, addr) {
+        (Some(v), _) if v.is_ipv4() != addr.is_ipv4() => {
+            return Err(anyhow!("bind_address does not match address family"));
+        }
+        (Some(v), _) => v,
+        (None, SocketAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+        (None, SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if !conf.bind_interface.is_empty() {
+        bind_device(&socket, &conf.bind_interface)?;
+    }
+
+    if conf.dscp != 0 {
+        // The DSCP value is stored in the upper 6 bits of the TOS / traffic class.
+        let tos = (conf.dscp as u32) << 2;
+        match addr {
+            SocketAddr::V4(_) => socket.set_tos(tos)?,
+            SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+        }
+    }
+
+    if conf.socket_recv_buffer_size != 0 {
+        socket.set_recv_buffer_size(conf.socket_recv_buffer_size)?;
+    }
+
+    if conf.socket_send_buffer_size != 0 {
+        socket.set_send_buffer_size(conf.socket_send_buffer_size)?;
+    }
+
+    socket.bind(&SocketAddr::new(bind_ip, bind_port).into())?;
+    socket.connect(&addr.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_device(_socket: &Socket, _interface: &str) -> Result<()> {
+    Err(anyhow!("bind_interface is not supported on this platform"))
+}
+
+// Returns the given server (hostname:port) with the port replaced by the given port.
+pub fn replace_port(server: &str, port: u16) -> Result<String> {
+    match server.rsplit_once(':') {
+        Some((host, _)) => Ok(format!("{}:{}", host, port)),
+        None => Err(anyhow!("server must be in hostname:port format")),
+    }
+}
+
+// Sort the addresses by the preferred IP version. The order of the resolver is
+// retained within each IP version.
+fn sort_addrs(mut addrs: Vec<SocketAddr>, ip_preference: IpPreference) -> Vec<SocketAddr> {
+    match ip_preference {
+        IpPreference::Any => {}
+        IpPreference::Ipv4 => addrs.sort_by_key(|v| !v.is_ipv4()),
+        IpPreference::Ipv6 => addrs.sort_by_key(|v| !v.is_ipv6()),
+    }
+    addrs
+}
+
+// Move the given address to the end of the addresses, so that the other resolved
+// addresses are tried first.
+fn move_to_end(mut addrs: Vec<SocketAddr>, addr: Option<SocketAddr>) -> Vec<SocketAddr> {
+    if let Some(addr) = addr {
+        addrs.sort_by_key(|v| *v == addr);
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_addrs() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:1700".parse().unwrap(),
+            "127.0.0.1:1700".parse().unwrap(),
+            "[::2]:1700".parse().unwrap(),
+        ];
+
+        assert_eq!(addrs, sort_addrs(addrs.clone(), IpPreference::Any));
+        assert_eq!(
+            vec![addrs[1], addrs[0], addrs[2]],
+            sort_addrs(addrs.clone(), IpPreference::Ipv4)
+        );
+        assert_eq!(
+            vec![addrs[0], addrs[2], addrs[1]],
+            sort_addrs(addrs.clone(), IpPreference::Ipv6)
+        );
+    }
+
+    #[test]
+    fn test_move_to_end() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:1700".parse().unwrap(),
+            "127.0.0.1:1700".parse().unwrap(),
+            "[::2]:1700".parse().unwrap(),
+        ];
+
+        assert_eq!(addrs, move_to_end(addrs.clone(), None));
+        assert_eq!(
+            vec![addrs[1], addrs[2], addrs[0]],
+            move_to_end(addrs.clone(), Some(addrs[0]))
+        );
+        assert_eq!(
+            addrs,
+            move_to_end(addrs.clone(), Some("127.0.0.2:1700".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_replace_port() {
+        assert_eq!(
+            "localhost:1701",
+            replace_port("localhost:1700", 1701).unwrap()
+        );
+        assert_eq!("[::1]:1701", replace_port("[::1]:1700", 1701).unwrap());
+        assert!(replace_port("localhost", 1701).is_err());
+    }
+
+    #[test]
+    fn test_connect() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = connect(
+            &Server::default(),
+            &server.local_addr().unwrap().to_string(),
+            0,
+            None,
+        )
+        .unwrap();
+
+        assert!(socket.local_addr().unwrap().is_ipv4());
+        assert_eq!(server.local_addr().unwrap(), socket.peer_addr().unwrap());
+    }
+
+    #[test]
+    fn test_connect_bind() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let socket = connect(
+            &Server {
+                bind_address: "127.0.0.1".into(),
+                dscp: 46,
+                ..Default::default()
+            },
+            &server.local_addr().unwrap().to_string(),
+            port,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            socket.local_addr().unwrap()
+        );
+
+        // Address family mismatch.
+        assert!(
+            connect(
+                &Server {
+                    bind_address: "::1".into(),
+                    ..Default::default()
+                },
+                &server.local_addr().unwrap().to_string(),
+                0,
+                None,
+            )
+            .is_err()
+        );
+    }
+}